@@ -1,99 +1,985 @@
-use crate::request::Request;
-use crate::response::Response;
-use crate::router::Router;
-use crate::thread_pool::ThreadPool;
+use crate::extensions::Extensions;
+use crate::middleware::{Middleware, Next};
+use crate::reader::{Limits, RequestError, RequestReader};
+use crate::request::{HttpMethod, Request};
+use crate::response::{error_response, Response};
+use crate::router::{route_handler, HandlerOutput, RouteError, RouteHandler, RouteMatch, Router};
+use crate::shutdown::{ConnectionGuard, ServerHandle};
+use crate::socket::{self, SocketOptions};
+use crate::thread_pool::{panic_message, ThreadPool};
 use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
+    io::{self, BufWriter, ErrorKind},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
     sync::Arc,
-    sync::Mutex,
+    thread,
+    time::Duration,
 };
 
+//
+//  Renders an error response, e.g. a 404 or a 400 for a malformed request, from its status
+//  and message, see `App::on_error`
+//
+pub type ErrorHandler = Arc<dyn Fn(usize, &str, &mut Response) + Send + Sync + 'static>;
+
 pub struct App {
-    routers: Vec<Router>,
-    thread_pool: ThreadPool,
+    handle: ServerHandle,
+    router: Router,
+    middleware: Vec<Middleware>,
+    not_found: RouteHandler,
+    on_error: Option<ErrorHandler>,
+    state: Extensions,
+    config: ServerConfig,
+}
+
+//
+//  How the server runs: its workers, socket timeouts and options, and the limits on what a
+//  client may send. Start from `ServerConfig::default()` and override what's needed.
+//
+#[derive(Debug, Clone, Copy)]
+pub struct ServerConfig {
+    //
+    //  Threads serving connections, each serves one connection at a time
+    //
+    pub workers: usize,
+    //
+    //  How long reading a request, once it has started, and writing a response may stall,
+    //  `None` waits forever
+    //
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub keep_alive: KeepAlive,
+    pub limits: Limits,
+    //
+    //  Connections the OS queues up before they're accepted, and the socket options set
+    //  before binding. Neither applies to a listener passed to `App::from_listener`.
+    //
+    pub backlog: u32,
+    pub reuse_address: bool,
+    pub reuse_port: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            workers: 10,
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            keep_alive: KeepAlive::default(),
+            limits: Limits::default(),
+            backlog: 128,
+            //
+            //  What std's `TcpListener::bind` does: Windows' SO_REUSEADDR would let another
+            //  socket take over the port
+            //
+            reuse_address: cfg!(unix),
+            reuse_port: false,
+        }
+    }
+}
+
+//
+//  A connection keeps its worker while it waits for its next request, which saves clients
+//  reconnecting but means at most `workers` connections are served at once. So that idle
+//  clients can't starve new ones, an idle connection is closed as soon as another one is
+//  waiting for a worker, whatever the idle timeout.
+//
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    //
+    //  How long an open connection may sit without sending a request, `None` waits forever
+    //
+    pub idle_timeout: Option<Duration>,
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Some(Duration::from_secs(5)),
+            max_requests: 100,
+        }
+    }
 }
 
 impl App {
     pub fn new() -> Self {
-        let thread_pool = match ThreadPool::new(10) {
-            Ok(pool) => pool,
-            Err(e) => {
-                println!("Application error: {}", e);
-                std::process::exit(1);
-            }
-        };
         Self {
-            routers: Vec::<Router>::new(),
-            thread_pool,
+            handle: ServerHandle::new(),
+            router: Router::new("/"),
+            middleware: Vec::<Middleware>::new(),
+            not_found: Arc::new(not_found_handler),
+            on_error: None,
+            state: Extensions::new(),
+            config: ServerConfig::default(),
         }
     }
 
-    pub fn add_router(&mut self, router: Router) {
-        self.routers.push(router);
+    //
+    //  Register a value shared by every request, available to handlers through `Request::state`.
+    //  Only one value is kept per type.
+    //
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Self {
+        self.state.insert(state);
+        self
+    }
+
+    //
+    //  Requests with larger headers are answered with a 431, larger bodies with a 413
+    //
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.config.limits = limits;
+        self
     }
 
+    //
+    //  Persistent connections are closed once idle for too long or after serving `max_requests`
+    //
+    pub fn with_keep_alive(mut self, keep_alive: KeepAlive) -> Self {
+        self.config.keep_alive = keep_alive;
+        self
+    }
+
+    //
+    //  Replaces the whole configuration, including limits and keep-alive settings
+    //
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    //
+    //  Every router's routes are mounted, at their own paths, in a single tree. Fails if one
+    //  clashes with a route already added, leaving the App's routes as they were.
+    //
+    pub fn add_router(&mut self, router: Router) -> Result<(), RouteError> {
+        self.router.nest("/", router)
+    }
+
+    //
+    //  Middleware added here runs, in order, for every request, before any
+    //  Router middleware and the matched route handler
+    //
+    pub fn use_middleware<F>(&mut self, middleware: F)
+    where
+        F: Fn(&mut Request, &mut Response, Next) + Send + Sync + 'static,
+    {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    //
+    //  Handle requests no route or router not-found handler matches, in place of the
+    //  default JSON 404. Runs after the App middleware.
+    //
+    pub fn not_found<F, R>(&mut self, handler: F)
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.not_found = route_handler(handler);
+    }
+
+    //
+    //  Render error responses, e.g. the default 404, a 405, a 400 for a parameter that
+    //  failed to parse or for a malformed request, in place of the default
+    //  `{"error": message}` JSON body. The handler gets the response with its status and
+    //  headers already set.
+    //
+    pub fn on_error<F>(&mut self, handler: F)
+    where
+        F: Fn(usize, &str, &mut Response) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Arc::new(handler));
+    }
+
+    //
+    //  A handle to stop `listen` from another thread, see `ServerHandle::shutdown`
+    //
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    //
+    //  Shorthand for `bind` and `Server::run`
+    //
     pub fn listen(&self, host: &str, port: usize) -> Result<(), std::io::Error> {
-        let listener = TcpListener::bind(format!("{}:{}", host, port))?;
-
-        for stream in listener.incoming() {
-            let mut stream = stream?;
-
-            //
-            //  Get an async-safe, wrapped, clone of the routers Vec
-            //
-            let routers = Arc::new(Mutex::new(self.routers.to_vec()));
-
-            match self.thread_pool.execute(move || {
-                //
-                //  Prepare the socket, request/response and the data needed to find the route
-                //
-                let incoming = read_incoming(&mut stream).unwrap();
-                let mut req = Request::new(incoming);
-                let mut res = Response::new();
-                let method = req.method();
-                let route = req.route();
-
-                //
-                //  Search for the correct route and any route params, otherwise return the default 404 route
-                //
-                let (handler, url_params) = routers
-                    .lock()
-                    .unwrap()
-                    .iter()
-                    .find_map(|r| r.match_handler(method, route))
-                    .unwrap_or((not_found_handler, None));
-
-                req.set_url_params(url_params);
-                //
-                //  Fire off the handler and then write to the client
-                //
-                //  @TODO: The handler should actually be a iterator of RouteHandlers that the req/res are fed into
-                //         until an error is thrown or the iterator is over
-                //
-                handler(&req, &mut res);
-
-                stream.write(&res.build_response()).unwrap();
-                stream.flush().unwrap();
-            }) {
+        self.bind(format!("{}:{}", host, port))?.run()
+    }
+
+    //
+    //  Bind to the first of the addresses that works, with the configured backlog and socket
+    //  options. Port 0 binds to a free port, see `Server::local_addr`.
+    //
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<Server<'_>> {
+        let options = SocketOptions {
+            backlog: self.config.backlog,
+            reuse_address: self.config.reuse_address,
+            reuse_port: self.config.reuse_port,
+        };
+        let mut last_error = None;
+        for addr in addr.to_socket_addrs()? {
+            match socket::bind(addr, options) {
+                Ok(listener) => return Ok(self.from_listener(listener)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+
+    //
+    //  Serve on a listener that's already bound, e.g. one inherited from a parent process
+    //
+    pub fn from_listener(&self, listener: TcpListener) -> Server<'_> {
+        Server {
+            app: self,
+            listener,
+        }
+    }
+}
+
+//
+//  An App bound to its listener, see `App::bind`
+//
+pub struct Server<'a> {
+    app: &'a App,
+    listener: TcpListener,
+}
+
+impl Server<'_> {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    //
+    //  Serve requests until the App's handle is shut down, then return once the connections
+    //  have been drained and the workers stopped
+    //
+    pub fn run(self) -> io::Result<()> {
+        let app = self.app;
+        let config = app.config;
+        let thread_pool = ThreadPool::new(config.workers)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        app.handle
+            .listening(self.listener.local_addr()?, config.workers);
+
+        //
+        //  Routes, middleware and state are only read while serving, so every connection shares one copy
+        //
+        let service = Arc::new(Service {
+            router: app.router.clone(),
+            middleware: app.middleware.to_vec(),
+            not_found: Arc::clone(&app.not_found),
+            on_error: app.on_error.clone(),
+            state: Arc::new(app.state.clone()),
+            config,
+            handle: app.handle.clone(),
+        });
+
+        //
+        //  A failed accept never stops the server. Errors about the one connection are
+        //  skipped, anything else, e.g. running out of file descriptors, is waited out with
+        //  a growing pause so the loop doesn't spin while it lasts.
+        //
+        //  The handle is checked before every accept as well as after: `shutdown` only wakes
+        //  the loop once the address is known, so one that came before `listening` above
+        //  has to be seen here.
+        //
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        while !app.handle.is_shutting_down() {
+            let accepted = self.listener.accept();
+            if app.handle.is_shutting_down() {
+                break;
+            }
+            let stream = match accepted {
+                Ok((stream, _)) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    stream
+                }
+                Err(e) if is_connection_error(&e) => continue,
+                Err(e) => {
+                    println!("Accept error: {}, retrying in {:?}", e, backoff);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            //
+            //  Tracked from here, so a connection still queued for a worker is waited for too
+            //
+            let connection = match app.handle.register(&stream) {
+                Ok(connection) => connection,
                 Err(e) => {
-                    println!("Application error: {}", e);
+                    println!("Connection error: {}", e);
+                    continue;
                 }
-                _ => {}
             };
+            let service = Arc::clone(&service);
+
+            if let Err(e) =
+                thread_pool.execute(move || service.serve_connection(stream, connection))
+            {
+                println!("Application error: {}", e);
+            }
         }
+
+        app.handle.drain();
+        //
+        //  The deadline closes connections, it can't stop threads: a handler that never
+        //  returns keeps this waiting past it
+        //
+        thread_pool.join();
         Ok(())
     }
 }
 
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Service {
+    handle: ServerHandle,
+    router: Router,
+    middleware: Vec<Middleware>,
+    not_found: RouteHandler,
+    on_error: Option<ErrorHandler>,
+    state: Arc<Extensions>,
+    config: ServerConfig,
+}
+
+impl Service {
+    //
+    //  Answer requests off of the connection, in order, until the client or the keep-alive
+    //  settings close it
+    //
+    fn serve_connection(&self, stream: TcpStream, connection: ConnectionGuard) {
+        if let Err(e) = stream.set_write_timeout(self.config.write_timeout) {
+            println!("Connection error: {}", e);
+            return;
+        }
+        let mut reader = RequestReader::new(&stream, self.config.limits);
+        let mut writer = BufWriter::new(&stream);
+
+        for served in 1.. {
+            //
+            //  Once the server is shutting down, a connection waiting for its next request
+            //  is closed rather than kept open
+            //
+            if served > 1 && !connection.set_idle(true) {
+                return;
+            }
+            //
+            //  Waiting for a request is bound by the idle timeout, reading the rest of it by
+            //  the read timeout
+            //
+            let waited = stream
+                .set_read_timeout(self.config.keep_alive.idle_timeout)
+                .and_then(|_| reader.wait_for_request())
+                .and_then(|started| {
+                    stream.set_read_timeout(self.config.read_timeout)?;
+                    Ok(started)
+                });
+            //
+            //  Busy from the first byte of a request on, so shutting down waits for it rather
+            //  than closing it halfway through
+            //
+            match waited {
+                Ok(true) if connection.set_idle(false) => {}
+                Ok(_) => return,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return,
+                Err(e) => {
+                    println!("Connection error: {}", e);
+                    return;
+                }
+            }
+            let (mut res, keep_alive, head, chunked) = match reader.read_request() {
+                Ok(Some(mut req)) => {
+                    let keep_alive =
+                        req.keep_alive() && served < self.config.keep_alive.max_requests;
+                    let head = *req.method() == HttpMethod::Head;
+                    //
+                    //  Chunked framing is only understood by HTTP/1.1 clients
+                    //
+                    let chunked = req.version() != "HTTP/1.0";
+                    req.set_state(Arc::clone(&self.state));
+                    (self.handle_request(req), keep_alive, head, chunked)
+                }
+                Ok(None) => return,
+                Err(RequestError::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    return
+                }
+                Err(e) => match e.status() {
+                    Some(status) => {
+                        let mut res = error_response(status, &e.to_string());
+                        self.render_error(&mut res);
+                        (res, false, false, true)
+                    }
+                    None => {
+                        println!("Connection error: {}", e);
+                        return;
+                    }
+                },
+            };
+
+            //
+            //  A handler can close the connection by setting `Connection: close` itself, and
+            //  it's closed once the server is shutting down
+            //
+            let keep_alive = keep_alive
+                && !self.handle.is_shutting_down()
+                && !res
+                    .get_header("Connection")
+                    .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+            res.set_header(
+                "Connection",
+                if keep_alive { "keep-alive" } else { "close" },
+            );
+            let written = if head {
+                res.write_head_to(&mut writer, chunked)
+            } else {
+                res.write_to(&mut writer, chunked)
+            };
+            if let Err(e) = written {
+                println!("Connection error: {}", e);
+                return;
+            }
+            //
+            //  An unframed body can only be ended by closing the connection
+            //
+            let unframed = res
+                .get_header("Connection")
+                .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+            if !keep_alive || unframed {
+                return;
+            }
+        }
+    }
+
+    fn handle_request(&self, mut req: Request) -> Response {
+        let mut res = Response::new();
+
+        //
+        //  Search for the correct route, any route params and the routers' middleware,
+        //  otherwise fall back to a 405, a router's not-found handler or the App's
+        //
+        let found = self
+            .router
+            .match_handler(req.method(), req.route())
+            .or_else(|| self.match_other_methods(req.method(), req.route()))
+            .or_else(|| self.router.match_fallback(req.route()))
+            .unwrap_or_else(|| RouteMatch {
+                handler: Arc::clone(&self.not_found),
+                params: None,
+                middleware: Vec::<Middleware>::new(),
+            });
+
+        req.set_url_params(found.params);
+
+        //
+        //  Feed the req/res through the app middleware, then the router middleware (outer
+        //  routers before nested ones) and finally the handler, until one of them stops
+        //  calling `next`
+        //
+        let chain = [&self.middleware[..], &found.middleware[..]].concat();
+        //
+        //  A panicking handler or middleware gets the client a 500 rather than a dropped
+        //  connection, and the connection is closed since its state can't be trusted
+        //
+        let ran = panic::catch_unwind(AssertUnwindSafe(|| {
+            Next::new(&chain, &found.handler).run(&mut req, &mut res)
+        }));
+        if let Err(panic) = ran {
+            println!(
+                "Handler panicked: {} {}: {}",
+                req.method().as_str(),
+                req.route(),
+                panic_message(&*panic)
+            );
+            res = error_response(500, "internal server error");
+            res.set_header("Connection", "close");
+        }
+        self.render_error(&mut res);
+        res
+    }
+
+    //
+    //  Let the App's error handler, if there is one, replace the default body of an error
+    //  response. It starts from the status and headers, minus the default Content-Type.
+    //
+    fn render_error(&self, res: &mut Response) {
+        let Some(message) = res.take_error() else {
+            return;
+        };
+        if let Some(on_error) = &self.on_error {
+            res.headers_mut().remove("Content-Type");
+            res.set_body_bytes(Vec::<u8>::new());
+            on_error(res.status(), &message, res);
+        }
+    }
+
+    //
+    //  A path that's routed under other methods gets a 405 listing them, or the list itself
+    //  for an OPTIONS request
+    //
+    fn match_other_methods(&self, method: &HttpMethod, route: &str) -> Option<RouteMatch> {
+        let mut allowed = self.router.allowed_methods(route);
+        if allowed.is_empty() {
+            return None;
+        }
+        allowed.push(HttpMethod::Options);
+        allowed.sort();
+        allowed.dedup();
+        let allow = allowed
+            .iter()
+            .map(HttpMethod::as_str)
+            .collect::<Vec<&str>>()
+            .join(", ");
+
+        let handler: RouteHandler = if *method == HttpMethod::Options {
+            Arc::new(move |_: &Request, res: &mut Response| {
+                res.set_header("Allow", &allow);
+                res.set_status(204);
+            })
+        } else {
+            Arc::new(move |_: &Request, res: &mut Response| {
+                res.set_header("Allow", &allow);
+                res.set_error(405, "method not allowed");
+            })
+        };
+        Some(RouteMatch {
+            handler,
+            params: None,
+            middleware: Vec::<Middleware>::new(),
+        })
+    }
+}
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+//
+//  Accept errors that only concern the connection being accepted, the next one is fine
+//
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+    )
+}
+
 fn not_found_handler(_: &Request, res: &mut Response) {
-    res.set_header("Content-Type", "application/json");
-    res.set_status(404);
-    res.set_body("{\"error\": \"not found\"}");
+    res.set_error(404, "not found");
 }
 
-fn read_incoming(socket: &mut TcpStream) -> Result<[u8; 1024], std::io::Error> {
-    let mut buf: [u8; 1024] = [0; 1024];
-    socket.read(&mut buf)?;
-    Ok(buf)
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{self, ErrorKind, Read, Write},
+        net::TcpStream,
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use super::{is_connection_error, App, KeepAlive, ServerConfig, Service};
+    use crate::{
+        extensions::Extensions,
+        middleware::Middleware,
+        request::Request,
+        response::Response,
+        router::{route_handler, RouteError, Router},
+        shutdown::ServerHandle,
+    };
+
+    fn service(routers: Vec<Router>) -> Service {
+        let mut router = Router::new("/");
+        for r in routers {
+            router.nest("/", r).unwrap();
+        }
+        Service {
+            handle: ServerHandle::new(),
+            router,
+            middleware: Vec::<Middleware>::new(),
+            not_found: Arc::new(super::not_found_handler),
+            on_error: None,
+            state: Arc::new(Extensions::new()),
+            config: ServerConfig::default(),
+        }
+    }
+
+    fn send(service: &Service, raw: &str) -> String {
+        let mut res = service.handle_request(Request::parse(raw.as_bytes()).unwrap());
+        String::from_utf8(res.build_response()).unwrap()
+    }
+
+    #[test]
+    fn answers_wrong_method_with_405() {
+        let noop = |_: &Request, _: &mut Response| {};
+        let mut users = Router::new("/");
+        users.get("/user/:id", noop).unwrap();
+        let mut admin = Router::new("/");
+        admin.delete("/user/:id", noop).unwrap();
+        let service = service(vec![users, admin]);
+
+        let built = send(&service, "PUT /user/7 HTTP/1.1\r\n\r\n");
+        assert!(built.starts_with("HTTP/1.1 405 Method Not Allowed"));
+        assert!(built.contains("Allow: GET, HEAD, DELETE, OPTIONS\r\n"));
+
+        let built = send(&service, "PUT /post/7 HTTP/1.1\r\n\r\n");
+        assert!(built.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(!built.contains("Allow:"));
+
+        let mut app = App::new();
+        let mut users = Router::new("/");
+        users.get("/user/:id", noop).unwrap();
+        app.add_router(users).unwrap();
+        let mut admin = Router::new("/");
+        admin.get("/user/:name", noop).unwrap();
+        assert!(matches!(
+            app.add_router(admin),
+            Err(RouteError::Conflict { .. })
+        ));
+    }
+
+    #[test]
+    fn answers_head_and_options_automatically() {
+        let mut router = Router::new("/");
+        router
+            .get("/user/:id", |_: &Request, res: &mut Response| {
+                res.set_body("{\"id\": 7}");
+            })
+            .unwrap();
+        router
+            .post("/user/:id", |_: &Request, _: &mut Response| {})
+            .unwrap();
+        let service = service(vec![router]);
+
+        let mut res =
+            service.handle_request(Request::parse(b"HEAD /user/7 HTTP/1.1\r\n\r\n").unwrap());
+        let mut buf = Vec::<u8>::new();
+        res.write_head_to(&mut buf, true).unwrap();
+        let built = String::from_utf8(buf).unwrap();
+        assert!(built.starts_with("HTTP/1.1 200 OK"));
+        assert!(built.contains("Content-Length: 9\r\n"));
+        assert!(built.ends_with("\r\n\r\n"));
+
+        let built = send(&service, "OPTIONS /user/7 HTTP/1.1\r\n\r\n");
+        assert!(built.starts_with("HTTP/1.1 204 No Content"));
+        assert!(built.contains("Allow: GET, HEAD, POST, OPTIONS\r\n"));
+
+        let built = send(&service, "OPTIONS /post/7 HTTP/1.1\r\n\r\n");
+        assert!(built.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[test]
+    fn uses_custom_not_found_and_error_handlers() {
+        let mut docs = Router::new("/");
+        docs.not_found(|req: &Request, res: &mut Response| {
+            res.set_status(404);
+            res.set_body(&format!("no docs for {}", req.route()));
+        })
+        .unwrap();
+        let mut api = Router::new("/");
+        api.get("/user/:id", |req: &Request, _: &mut Response| {
+            req.param::<u64>("id").map(|_| ())
+        })
+        .unwrap();
+        api.nest("/docs", docs).unwrap();
+        let mut service = service(vec![api]);
+
+        let built = send(&service, "GET /docs/intro HTTP/1.1\r\n\r\n");
+        assert!(built.ends_with("\r\n\r\nno docs for /docs/intro"));
+        let built = send(&service, "GET /blog HTTP/1.1\r\n\r\n");
+        assert!(built.ends_with("{\"error\": \"not found\"}"));
+
+        service.not_found =
+            route_handler(|_: &Request, res: &mut Response| res.set_error(404, "nothing here"));
+        service.on_error = Some(Arc::new(
+            |status: usize, message: &str, res: &mut Response| {
+                res.set_header("Content-Type", "text/html");
+                res.set_body(&format!("<h1>{}</h1><p>{}</p>", status, message));
+            },
+        ));
+        let built = send(&service, "GET /blog HTTP/1.1\r\n\r\n");
+        assert!(built.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(built.contains("Content-Type: text/html\r\n"));
+        assert!(built.ends_with("<h1>404</h1><p>nothing here</p>"));
+
+        let built = send(&service, "GET /user/abc HTTP/1.1\r\n\r\n");
+        assert!(built.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(!built.contains("application/json"));
+        assert!(built.contains("<p>invalid path parameter 'id' = 'abc'"));
+    }
+
+    #[test]
+    fn turns_handler_panics_into_500() {
+        let mut router = Router::new("/");
+        fn boom(_: &Request, res: &mut Response) {
+            res.set_body("partial");
+            panic!("handler bug");
+        }
+        router.get("/boom", boom).unwrap();
+        let service = service(vec![router]);
+
+        let built = send(&service, "GET /boom HTTP/1.1\r\n\r\n");
+        assert!(built.starts_with("HTTP/1.1 500 Internal Server Error"));
+        assert!(built.contains("Connection: close\r\n"));
+        assert!(built.ends_with("{\"error\": \"internal server error\"}"));
+    }
+
+    #[test]
+    fn skips_only_per_connection_accept_errors() {
+        let error = |kind: ErrorKind| io::Error::from(kind);
+        assert!(is_connection_error(&error(ErrorKind::ConnectionAborted)));
+        assert!(is_connection_error(&error(ErrorKind::Interrupted)));
+        assert!(!is_connection_error(&io::Error::from_raw_os_error(24)));
+        assert!(!is_connection_error(&error(ErrorKind::OutOfMemory)));
+    }
+
+    #[test]
+    fn drains_in_flight_requests_on_shutdown() {
+        let (started, handler_started) = mpsc::channel::<()>();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let mut app = App::new();
+        let mut router = Router::new("/");
+        router
+            .get("/slow", move |_: &Request, res: &mut Response| {
+                started.send(()).unwrap();
+                released.lock().unwrap().recv().unwrap();
+                res.set_body("done");
+            })
+            .unwrap();
+        router
+            .post("/up", |req: &Request, res: &mut Response| {
+                res.set_body(&String::from_utf8_lossy(req.body()));
+            })
+            .unwrap();
+        app.add_router(router).unwrap();
+        let handle = app.handle();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || app.from_listener(listener).run());
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        //
+        //  A second, keep-alive, connection is halfway through sending its next request
+        //
+        let wait_until = |done: &dyn Fn() -> bool| {
+            for _ in 0..5000 {
+                if done() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            panic!("timed out");
+        };
+        let mut uploader = TcpStream::connect(addr).unwrap();
+        uploader
+            .write_all(b"POST /up HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi")
+            .unwrap();
+        let mut buf = [0; 1024];
+        let mut first = Vec::<u8>::new();
+        while !first.ends_with(b"hi") {
+            let read = uploader.read(&mut buf).unwrap();
+            assert_ne!(read, 0);
+            first.extend_from_slice(&buf[..read]);
+        }
+        wait_until(&|| handle.idle_connections() == 1);
+        uploader
+            .write_all(b"POST /up HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello")
+            .unwrap();
+        wait_until(&|| handle.idle_connections() == 0);
+
+        //
+        //  Shut down while both are in flight, then let them finish
+        //
+        handler_started
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        handle.shutdown(Duration::from_secs(5));
+        uploader.write_all(b"world").unwrap();
+        release.send(()).unwrap();
+
+        server.join().unwrap().unwrap();
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("done"));
+        let mut response = String::new();
+        uploader.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("helloworld"));
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn closes_idle_connections_for_waiting_ones() {
+        let mut app = App::new().with_config(ServerConfig {
+            workers: 1,
+            keep_alive: KeepAlive {
+                idle_timeout: Some(Duration::from_secs(60)),
+                max_requests: 100,
+            },
+            ..ServerConfig::default()
+        });
+        let mut router = Router::new("/");
+        router
+            .get("/", |_: &Request, res: &mut Response| res.set_body("hi"))
+            .unwrap();
+        app.add_router(router).unwrap();
+        let handle = app.handle();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || app.from_listener(listener).run());
+
+        let request = |stream: &mut TcpStream| {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            let mut buf = [0; 1024];
+            let read = stream.read(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..read]).to_string()
+        };
+        let mut idle = TcpStream::connect(addr).unwrap();
+        assert!(request(&mut idle).ends_with("hi"));
+        //
+        //  The only worker is held by the idle connection until the new one shows up
+        //
+        let mut waiting = TcpStream::connect(addr).unwrap();
+        assert!(request(&mut waiting).ends_with("hi"));
+        assert_eq!(idle.read(&mut [0; 1024]).unwrap(), 0);
+
+        handle.shutdown(Duration::from_secs(1));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn streams_unframed_bodies_to_http_1_0_clients() {
+        let mut app = App::new();
+        let mut router = Router::new("/");
+        router
+            .get("/reader", |_: &Request, res: &mut Response| {
+                res.set_body_reader(&b"from a reader"[..], None)
+            })
+            .unwrap();
+        router
+            .get("/writer", |_: &Request, res: &mut Response| {
+                res.set_body_writer(|w: &mut dyn Write| w.write_all(b"from a writer"))
+            })
+            .unwrap();
+        app.add_router(router).unwrap();
+        let handle = app.handle();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || app.from_listener(listener).run());
+
+        for (path, expected) in [("/reader", "from a reader"), ("/writer", "from a writer")] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            write!(stream, "GET {} HTTP/1.0\r\n\r\n", path).unwrap();
+            //
+            //  The body ends where the connection does
+            //
+            let mut built = String::new();
+            stream.read_to_string(&mut built).unwrap();
+            let (head, body) = built.split_once("\r\n\r\n").unwrap();
+            assert!(!head.contains("Transfer-Encoding"));
+            assert!(head.contains("Connection: close\r\n"));
+            assert_eq!(body, expected);
+        }
+
+        handle.shutdown(Duration::from_secs(1));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn stops_when_shut_down_before_running() {
+        let app = App::new();
+        let server = app.bind("127.0.0.1:0").unwrap();
+        app.handle().shutdown(Duration::from_secs(1));
+        server.run().unwrap();
+    }
+
+    #[test]
+    fn binds_port_zero_and_rejects_zero_workers() {
+        let app = App::new();
+        let server = app.bind(("127.0.0.1", 0)).unwrap();
+        assert_ne!(server.local_addr().unwrap().port(), 0);
+
+        let app = App::new().with_config(ServerConfig {
+            workers: 0,
+            ..ServerConfig::default()
+        });
+        let server = app.bind("127.0.0.1:0").unwrap();
+        let e = server.run().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn times_out_slow_requests_and_idle_connections() {
+        //
+        //  The idle timeout is far longer than the test runs, so only the read timeout can
+        //  close the connection
+        //
+        let read_timeout = Duration::from_millis(100);
+        let mut app = App::new().with_config(ServerConfig {
+            workers: 2,
+            read_timeout: Some(read_timeout),
+            keep_alive: KeepAlive {
+                idle_timeout: Some(Duration::from_secs(60)),
+                max_requests: 100,
+            },
+            ..ServerConfig::default()
+        });
+        let mut router = Router::new("/");
+        router
+            .get("/", |_: &Request, res: &mut Response| res.set_body("hi"))
+            .unwrap();
+        app.add_router(router).unwrap();
+        let handle = app.handle();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || app.from_listener(listener).run());
+
+        //
+        //  Sitting idle for at least the read timeout is fine, stalling halfway through a
+        //  request isn't
+        //
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        thread::sleep(read_timeout * 2);
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut buf = [0; 1024];
+        let read = stream.read(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..read]).ends_with("hi"));
+        stream.write_all(b"GET / HTT").unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        handle.shutdown(Duration::from_secs(1));
+        server.join().unwrap().unwrap();
+    }
 }
@@ -1,24 +1,57 @@
-use http::{app::App, request::Request, response::Response, router::Router};
-use std::thread;
+use http::{
+    app::App, error::HttpError, middleware::Next, request::Request, response::Response,
+    router::Router,
+};
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
 
-fn main() -> Result<(), std::io::Error> {
-    let mut app = App::new();
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut app = App::new().with_state(Config {
+        name: "simple".to_string(),
+    });
+    app.use_middleware(logger);
     let mut main_router = Router::new("/");
 
-    main_router.get("/", index_route);
-    main_router.get("/sleep", sleep_route);
-    main_router.get("/user/:user_id/post/:post_id", user_post);
+    main_router.get("/", index_route)?;
+    main_router.get("/sleep", sleep_route)?;
+    main_router.get("/user/:user_id/post/:post_id", user_post)?;
+    main_router.get("/report", report_route)?;
 
-    app.add_router(main_router);
-    app.listen("127.0.0.1", 3000)
+    let visits = Arc::new(AtomicUsize::new(0));
+    main_router.get("/visits", move |_: &Request, res: &mut Response| {
+        let count = visits.fetch_add(1, Ordering::SeqCst) + 1;
+        res.set_header("Content-Type", "application/json");
+        res.set_body(&format!("{{\"visits\": {}}}", count));
+    })?;
+
+    app.add_router(main_router)?;
+    app.listen("127.0.0.1", 3000)?;
+    Ok(())
+}
+
+struct Config {
+    name: String,
+}
+
+fn logger(req: &mut Request, res: &mut Response, next: Next) {
+    println!("--> {} {}", req.method(), req.route());
+    next.run(req, res);
 }
 
 fn index_route(req: &Request, res: &mut Response) {
     println!("Index route: {} with Method: {}", req.route(), req.method());
 
-    match req.get_header("Content-Type") {
-        Some(s) => println!("{}", s),
-        None => {}
+    if let Some(config) = req.state::<Config>() {
+        println!("Served by: {}", config.name);
+    }
+    if let Some(s) = req.get_header("Content-Type") {
+        println!("{}", s);
     }
     println!("Body: \n{}\n", String::from_utf8_lossy(req.body()));
     res.set_header("Content-Type", "application/json");
@@ -34,18 +67,27 @@ fn sleep_route(req: &Request, res: &mut Response) {
     res.set_body("{\"page\": \"sleep\"}");
 }
 
-fn user_post(req: &Request, res: &mut Response) {
+fn user_post(req: &Request, res: &mut Response) -> Result<(), HttpError> {
     println!(
         "User Profile route: {} with Method: {}",
         req.route(),
         req.method()
     );
+    let user_id = req.param::<u64>("user_id")?;
+    let post_id = req.param::<u64>("post_id")?;
+    if post_id == 0 {
+        return Err(HttpError::not_found("no such post"));
+    }
     res.set_header("Content-Type", "application/json");
     res.set_status(200);
-    let user_id = req.get_url_param("user_id").unwrap();
-    let post_id = req.get_url_param("post_id").unwrap();
     res.set_body(&format!(
-        "{{\"page\": \"post\", \"post\": {{ \"user\": \"{}\", \"id\": \"{}\", \"length\": \"length\" }} }}",
+        "{{\"page\": \"post\", \"post\": {{ \"user\": {}, \"id\": {}, \"length\": \"length\" }} }}",
         user_id, post_id
     ));
+    Ok(())
+}
+
+fn report_route(_: &Request, res: &mut Response) {
+    res.set_header("Content-Type", "text/csv");
+    res.set_body_chunks((1..=5).map(|row| format!("row,{}\n", row).into_bytes()));
 }
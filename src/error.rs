@@ -0,0 +1,152 @@
+use std::fmt::Display;
+
+use crate::{extract::ParamError, response::Response};
+
+//
+//  Implemented by errors a handler can return: turns the error into the response sent in
+//  place of whatever the handler had set
+//
+pub trait IntoResponse {
+    fn into_response(self, res: &mut Response);
+}
+
+//
+//  An error response: the status, the message sent to the client and, for logging only, the
+//  error that caused it
+//
+#[derive(Debug)]
+pub struct HttpError {
+    status: usize,
+    message: String,
+    source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
+}
+
+impl HttpError {
+    pub fn new(status: usize, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+            source: None,
+        }
+    }
+
+    pub fn bad_request(message: &str) -> Self {
+        Self::new(400, message)
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        Self::new(404, message)
+    }
+
+    pub fn internal(message: &str) -> Self {
+        Self::new(500, message)
+    }
+
+    pub fn with_source<E>(mut self, source: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    pub fn status(&self) -> usize {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{} {}: {}", self.status, self.message, source),
+            None => write!(f, "{} {}", self.status, self.message),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|source| source.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self, res: &mut Response) {
+        res.set_error(self.status, &self.message);
+    }
+}
+
+impl IntoResponse for ParamError {
+    fn into_response(self, res: &mut Response) {
+        res.set_error(self.status(), &self.to_string());
+    }
+}
+
+impl From<ParamError> for HttpError {
+    fn from(e: ParamError) -> Self {
+        Self::new(e.status(), &e.to_string())
+    }
+}
+
+//
+//  An I/O failure is the server's problem, so the client only sees a generic 500
+//
+impl From<std::io::Error> for HttpError {
+    fn from(e: std::io::Error) -> Self {
+        Self::internal("internal server error").with_source(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use super::HttpError;
+    use crate::{request::Request, response::Response, router::Router};
+
+    #[test]
+    fn keeps_source_out_of_the_response() {
+        let e = HttpError::from(std::io::Error::other("disk on fire"));
+        assert_eq!(e.to_string(), "500 internal server error: disk on fire");
+        assert!(e.source().is_some());
+
+        let mut router = Router::new("/");
+        router
+            .get("/report", |_: &Request, _: &mut Response| {
+                Err(HttpError::from(std::io::Error::other("disk on fire")))
+            })
+            .unwrap();
+        router
+            .get("/user/:id", |req: &Request, res: &mut Response| {
+                let id = req.param::<u64>("id")?;
+                if id > 100 {
+                    return Err(HttpError::not_found("no such user"));
+                }
+                res.set_body(&id.to_string());
+                Ok(())
+            })
+            .unwrap();
+
+        let run = |raw: &str| {
+            let mut req = Request::parse(raw.as_bytes()).unwrap();
+            let found = router.match_handler(req.method(), req.route()).unwrap();
+            req.set_url_params(found.params);
+            let mut res = Response::new();
+            (found.handler)(&req, &mut res);
+            String::from_utf8(res.build_response()).unwrap()
+        };
+
+        let built = run("GET /report HTTP/1.1\r\n\r\n");
+        assert!(built.starts_with("HTTP/1.1 500 Internal Server Error"));
+        assert!(built.ends_with("{\"error\": \"internal server error\"}"));
+        assert!(run("GET /user/7 HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n7"));
+        assert!(run("GET /user/700 HTTP/1.1\r\n\r\n").ends_with("{\"error\": \"no such user\"}"));
+        assert!(run("GET /user/x HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 400 Bad Request"));
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::Arc,
+};
+
+//
+//  A map holding at most one value of each type. Values are reference counted, so
+//  cloning the map is cheap and shares the values rather than copying them.
+//
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::<TypeId, Arc<dyn Any + Send + Sync>>::new(),
+        }
+    }
+
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+    }
+
+    //
+    //  Only available while the value is not shared with a clone of this map
+    //
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(Arc::get_mut)
+            .and_then(|value| value.downcast_mut::<T>())
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<Arc<T>> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast::<T>().ok())
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Extensions;
+
+    #[derive(Debug, PartialEq)]
+    struct User(String);
+
+    #[test]
+    fn stores_one_value_per_type() {
+        let mut ext = Extensions::new();
+        ext.insert(User("alice".to_string()));
+        ext.insert(42u32);
+        ext.insert(User("bob".to_string()));
+
+        assert_eq!(ext.get::<User>(), Some(&User("bob".to_string())));
+        assert_eq!(ext.get::<u32>(), Some(&42));
+        assert_eq!(ext.get::<u64>(), None);
+    }
+
+    #[test]
+    fn clones_share_values() {
+        let mut ext = Extensions::new();
+        ext.insert(1u8);
+        *ext.get_mut::<u8>().unwrap() += 1;
+
+        let cloned = ext.clone();
+        assert_eq!(cloned.get::<u8>(), Some(&2));
+        assert!(ext.get_mut::<u8>().is_none());
+    }
+}
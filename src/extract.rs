@@ -0,0 +1,188 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamError {
+    Missing {
+        kind: &'static str,
+        name: String,
+    },
+    Invalid {
+        kind: &'static str,
+        name: String,
+        value: String,
+        reason: String,
+    },
+    UnsupportedMediaType(String),
+}
+
+impl ParamError {
+    pub fn status(&self) -> usize {
+        match self {
+            Self::Missing { .. } | Self::Invalid { .. } => 400,
+            Self::UnsupportedMediaType(_) => 415,
+        }
+    }
+}
+
+impl std::fmt::Display for ParamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing { kind, name } => write!(f, "missing {} parameter '{}'", kind, name),
+            Self::Invalid {
+                kind,
+                name,
+                value,
+                reason,
+            } => write!(
+                f,
+                "invalid {} parameter '{}' = '{}': {}",
+                kind, name, value, reason
+            ),
+            Self::UnsupportedMediaType(content_type) => {
+                write!(f, "unsupported content type '{}'", content_type)
+            }
+        }
+    }
+}
+
+//
+//  Named, possibly repeated, string parameters, e.g. a query string or a form body, read
+//  into typed values through `FromStr`
+//
+pub struct Params<'a> {
+    kind: &'static str,
+    values: &'a HashMap<String, Vec<String>>,
+}
+
+impl<'a> Params<'a> {
+    pub fn new(kind: &'static str, values: &'a HashMap<String, Vec<String>>) -> Self {
+        Self { kind, values }
+    }
+
+    pub fn get<T>(&self, name: &str) -> Result<T, ParamError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.optional(name)?.ok_or_else(|| ParamError::Missing {
+            kind: self.kind,
+            name: name.to_string(),
+        })
+    }
+
+    pub fn optional<T>(&self, name: &str) -> Result<Option<T>, ParamError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.values
+            .get(name)
+            .and_then(|values| values.first())
+            .map(|value| parse_value(self.kind, name, value))
+            .transpose()
+    }
+
+    //
+    //  Every value of a repeated parameter, in order; empty when it's missing
+    //
+    pub fn all<T>(&self, name: &str) -> Result<Vec<T>, ParamError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.values
+            .get(name)
+            .map(|values| {
+                values
+                    .iter()
+                    .map(|value| parse_value(self.kind, name, value))
+                    .collect()
+            })
+            .unwrap_or_else(|| Ok(Vec::<T>::new()))
+    }
+}
+
+//
+//  Implemented by structs read out of a query string or form body, see `Request::query`
+//  and `Request::form`
+//
+pub trait FromParams: Sized {
+    fn from_params(params: &Params) -> Result<Self, ParamError>;
+}
+
+pub fn parse_value<T>(kind: &'static str, name: &str, value: &str) -> Result<T, ParamError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse::<T>().map_err(|e| ParamError::Invalid {
+        kind,
+        name: name.to_string(),
+        value: value.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FromParams, ParamError, Params};
+    use crate::request::Request;
+
+    #[derive(Debug, PartialEq)]
+    struct Filter {
+        page: u32,
+        tags: Vec<String>,
+        limit: Option<u8>,
+    }
+
+    impl FromParams for Filter {
+        fn from_params(params: &Params) -> Result<Self, ParamError> {
+            Ok(Self {
+                page: params.get("page")?,
+                tags: params.all("tag")?,
+                limit: params.optional("limit")?,
+            })
+        }
+    }
+
+    #[test]
+    fn reads_query_into_struct() {
+        let req = Request::parse(b"GET /?page=2&tag=a&tag=b HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(
+            req.query::<Filter>(),
+            Ok(Filter {
+                page: 2,
+                tags: vec!["a".to_string(), "b".to_string()],
+                limit: None,
+            })
+        );
+    }
+
+    #[test]
+    fn reports_missing_and_invalid_params() {
+        let req = Request::parse(b"GET /?tag=a HTTP/1.1\r\n\r\n").unwrap();
+        let err = req.query::<Filter>().unwrap_err();
+        assert_eq!(err.status(), 400);
+        assert_eq!(err.to_string(), "missing query parameter 'page'");
+
+        let req = Request::parse(b"GET /?page=two HTTP/1.1\r\n\r\n").unwrap();
+        let err = req.query::<Filter>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid query parameter 'page' = 'two': invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn reads_form_body() {
+        let req = Request::parse(
+            b"POST / HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\npage=1&limit=10",
+        )
+        .unwrap();
+        assert_eq!(req.form::<Filter>().unwrap().limit, Some(10));
+
+        let req =
+            Request::parse(b"POST / HTTP/1.1\r\nContent-Type: text/plain\r\n\r\npage=1").unwrap();
+        assert_eq!(req.form::<Filter>().unwrap_err().status(), 415);
+    }
+}
@@ -0,0 +1,178 @@
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    InvalidName(String),
+    InvalidValue(String),
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidName(name) => write!(f, "invalid header name: {:?}", name),
+            Self::InvalidValue(value) => write!(f, "invalid header value: {:?}", value),
+        }
+    }
+}
+
+//
+//  Header fields in the order they were added. Names keep their original case but are
+//  looked up case-insensitively, and a name can hold several values, e.g. Set-Cookie.
+//
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::<(String, String)>::new(),
+        }
+    }
+
+    //
+    //  The first value for `name`
+    //
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&String> {
+        self.entries
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    //
+    //  Replace every value of `name` with `value`
+    //
+    pub fn insert(&mut self, name: &str, value: &str) -> Result<(), HeaderError> {
+        validate(name, value)?;
+        match self
+            .entries
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(name))
+        {
+            Some(pos) => {
+                self.entries[pos] = (name.to_string(), value.to_string());
+                let mut i = pos + 1;
+                while i < self.entries.len() {
+                    if self.entries[i].0.eq_ignore_ascii_case(name) {
+                        self.entries.remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            None => self.entries.push((name.to_string(), value.to_string())),
+        }
+        Ok(())
+    }
+
+    //
+    //  Add `value` after any existing values of `name`
+    //
+    pub fn append(&mut self, name: &str, value: &str) -> Result<(), HeaderError> {
+        validate(name, value)?;
+        self.entries.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    //
+    //  Remove every value of `name`, returning whether there were any
+    //
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        self.entries.len() != len
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+//
+//  Names have to be RFC 9110 tokens. Values may hold visible characters, spaces, tabs and
+//  obs-text, but never CR, LF or NUL, so they can't be used to inject header lines.
+//
+fn validate(name: &str, value: &str) -> Result<(), HeaderError> {
+    if name.is_empty() || !name.bytes().all(is_token_char) {
+        return Err(HeaderError::InvalidName(name.to_string()));
+    }
+    if !value
+        .bytes()
+        .all(|b| b == b'\t' || b == b' ' || (0x21..=0x7e).contains(&b) || b >= 0x80)
+    {
+        return Err(HeaderError::InvalidValue(value.to_string()));
+    }
+    Ok(())
+}
+
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HeaderError, HeaderMap};
+
+    #[test]
+    fn looks_up_case_insensitively() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", "text/html").unwrap();
+
+        assert_eq!(headers.get("content-type").unwrap(), "text/html");
+        assert!(headers.contains("CONTENT-TYPE"));
+        headers.insert("content-TYPE", "text/plain").unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get("Content-Type").unwrap(), "text/plain");
+    }
+
+    #[test]
+    fn keeps_repeated_values() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1").unwrap();
+        headers.append("set-cookie", "b=2").unwrap();
+        headers.append("Accept", "text/html").unwrap();
+
+        assert_eq!(headers.get_all("Set-Cookie"), vec!["a=1", "b=2"]);
+        assert_eq!(headers.get("Set-Cookie").unwrap(), "a=1");
+
+        headers.insert("Set-Cookie", "c=3").unwrap();
+        assert_eq!(headers.get_all("set-cookie"), vec!["c=3"]);
+        assert!(headers.remove("SET-COOKIE"));
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_names_and_values() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            headers.insert("Bad Name", "x"),
+            Err(HeaderError::InvalidName("Bad Name".to_string()))
+        );
+        assert_eq!(
+            headers.append("X-Injected", "a\r\nSet-Cookie: b"),
+            Err(HeaderError::InvalidValue("a\r\nSet-Cookie: b".to_string()))
+        );
+        assert!(headers.insert("", "x").is_err());
+        assert!(headers.is_empty());
+    }
+}
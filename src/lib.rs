@@ -1,6 +1,16 @@
 pub mod app;
+mod date;
+pub mod error;
+pub mod extensions;
+pub mod extract;
+pub mod header;
 mod matcher;
+pub mod middleware;
+pub mod reader;
 pub mod request;
 pub mod response;
 pub mod router;
+pub mod shutdown;
+mod socket;
 mod thread_pool;
+mod urlencoded;
@@ -1,11 +1,16 @@
 #[derive(PartialEq, Debug)]
 pub enum Token {
     Path(String),
-    Param(String),
+    //
+    //  A `:name` param, with the constraint written after it as `:name(regex)` or `:name<type>`
+    //
+    Param(String, Option<String>),
     Slash,
     QuestionMark,
+    Asterisk,
     Ampersand,
     EqualSign,
+    Illegal(String),
     End,
 }
 
@@ -19,6 +24,10 @@ pub struct Lexer {
 
 impl Lexer {
     pub fn new(input: &str) -> Self {
+        //
+        //  An empty path is the index path
+        //
+        let input = if input.is_empty() { "/" } else { input };
         let chars: Vec<char> = input.chars().collect();
         let cur = chars[0];
         Self {
@@ -44,6 +53,10 @@ impl Lexer {
                 self.read_char();
                 Token::QuestionMark
             }
+            '*' => {
+                self.read_char();
+                Token::Asterisk
+            }
             '&' => {
                 self.read_char();
                 Token::Ampersand
@@ -80,19 +93,59 @@ impl Lexer {
     fn parse_param(&mut self) -> Token {
         self.read_char();
         let position = self.pos;
-        while self.pos < self.chars.len() && valid_path_char(&self.cur) {
+        while self.pos < self.chars.len() && valid_param_char(&self.cur) {
             self.read_char();
         }
-        let path = String::from_iter(&self.chars[position..self.pos]);
-        Token::Param(path)
+        let name = String::from_iter(&self.chars[position..self.pos]);
+        let constraint = match self.cur {
+            '(' => match self.read_delimited('(', ')') {
+                Some(constraint) => Some(constraint),
+                None => return Token::Illegal(format!("unclosed '(' after :{}", name)),
+            },
+            '<' => match self.read_delimited('<', '>') {
+                Some(constraint) => Some(constraint),
+                None => return Token::Illegal(format!("unclosed '<' after :{}", name)),
+            },
+            _ => None,
+        };
+        Token::Param(name, constraint)
+    }
+
+    //
+    //  Read from an opening delimiter to its matching close, skipping escaped characters and
+    //  nested pairs, returning what was between them
+    //
+    fn read_delimited(&mut self, open: char, close: char) -> Option<String> {
+        self.read_char();
+        let position = self.pos;
+        let mut depth = 0;
+        while self.pos < self.chars.len() {
+            match self.cur {
+                '\\' => self.read_char(),
+                c if c == open => depth += 1,
+                c if c == close && depth == 0 => {
+                    let inner = String::from_iter(&self.chars[position..self.pos]);
+                    self.read_char();
+                    return Some(inner);
+                }
+                c if c == close => depth -= 1,
+                _ => {}
+            }
+            self.read_char();
+        }
+        None
     }
 }
 
 fn valid_path_char(ch: &char) -> bool {
-    let invalid_chars = [':', '/', '\0', '?', '=', '&'];
+    let invalid_chars = [':', '/', '\0', '?', '=', '&', '*'];
     !invalid_chars.contains(ch)
 }
 
+fn valid_param_char(ch: &char) -> bool {
+    valid_path_char(ch) && !['(', '<'].contains(ch)
+}
+
 #[cfg(test)]
 mod test {
     use super::{Lexer, Token};
@@ -103,14 +156,55 @@ mod test {
         let mut lexer = Lexer::new(path);
 
         assert_eq!(lexer.next_token(), Token::Slash);
-        assert_eq!(lexer.next_token(), Token::Param("user".to_string()));
+        assert_eq!(lexer.next_token(), Token::Param("user".to_string(), None));
         assert_eq!(lexer.next_token(), Token::Slash);
-        assert_eq!(lexer.next_token(), Token::Param("id".to_string()));
+        assert_eq!(lexer.next_token(), Token::Param("id".to_string(), None));
         assert_eq!(lexer.next_token(), Token::Slash);
         assert_eq!(lexer.next_token(), Token::Path("preferences".to_string()));
         assert_eq!(lexer.next_token(), Token::End);
     }
 
+    #[test]
+    fn reads_param_constraints() {
+        let mut lexer = Lexer::new(r"/:id(\d+)/:slug<[a-z(-]+>/:n<uint>");
+
+        assert_eq!(lexer.next_token(), Token::Slash);
+        assert_eq!(
+            lexer.next_token(),
+            Token::Param("id".to_string(), Some(r"\d+".to_string()))
+        );
+        assert_eq!(lexer.next_token(), Token::Slash);
+        assert_eq!(
+            lexer.next_token(),
+            Token::Param("slug".to_string(), Some("[a-z(-]+".to_string()))
+        );
+        assert_eq!(lexer.next_token(), Token::Slash);
+        assert_eq!(
+            lexer.next_token(),
+            Token::Param("n".to_string(), Some("uint".to_string()))
+        );
+        assert_eq!(lexer.next_token(), Token::End);
+
+        let mut lexer = Lexer::new(r"/:id(\d+");
+        lexer.next_token();
+        assert!(matches!(lexer.next_token(), Token::Illegal(_)));
+    }
+
+    #[test]
+    fn reads_wildcards_and_modifiers() {
+        let mut lexer = Lexer::new("/*/:id?/*rest");
+
+        assert_eq!(lexer.next_token(), Token::Slash);
+        assert_eq!(lexer.next_token(), Token::Asterisk);
+        assert_eq!(lexer.next_token(), Token::Slash);
+        assert_eq!(lexer.next_token(), Token::Param("id".to_string(), None));
+        assert_eq!(lexer.next_token(), Token::QuestionMark);
+        assert_eq!(lexer.next_token(), Token::Slash);
+        assert_eq!(lexer.next_token(), Token::Asterisk);
+        assert_eq!(lexer.next_token(), Token::Path("rest".to_string()));
+        assert_eq!(lexer.next_token(), Token::End);
+    }
+
     #[test]
     fn default_index_path() {
         let path = "/";
@@ -1,89 +1,406 @@
 mod lexer;
+mod tree;
 
 use std::collections::HashMap;
 
 use lexer::Lexer;
+use regex::Regex;
 
 use self::lexer::Token;
+pub use self::tree::RouteTree;
+
+//
+//  What a constrained param has to look like: `:id<uint>` names a type the value has to
+//  parse as, anything else, like `:id(\d+)` or `:slug<[a-z-]+>`, is a regex
+//
+#[derive(Debug, Clone)]
+struct Constraint {
+    source: String,
+    kind: ConstraintKind,
+}
+
+#[derive(Debug, Clone)]
+enum ConstraintKind {
+    Int,
+    Uint,
+    Float,
+    Bool,
+    Pattern(Regex),
+}
+
+impl Constraint {
+    fn new(source: &str) -> Result<Self, String> {
+        let kind = match source {
+            "int" | "i64" => ConstraintKind::Int,
+            "uint" | "u64" => ConstraintKind::Uint,
+            "float" | "f64" => ConstraintKind::Float,
+            "bool" => ConstraintKind::Bool,
+            "alpha" => ConstraintKind::Pattern(whole_match("[a-zA-Z]+")?),
+            "alnum" => ConstraintKind::Pattern(whole_match("[a-zA-Z0-9]+")?),
+            "uuid" => ConstraintKind::Pattern(whole_match(
+                "[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}",
+            )?),
+            source => ConstraintKind::Pattern(whole_match(source)?),
+        };
+        Ok(Self {
+            source: source.to_string(),
+            kind,
+        })
+    }
+
+    fn allows(&self, value: &str) -> bool {
+        if value.len() > MAX_CONSTRAINED_LEN {
+            return false;
+        }
+        match &self.kind {
+            ConstraintKind::Int => value.parse::<i64>().is_ok(),
+            ConstraintKind::Uint => value.parse::<u64>().is_ok(),
+            ConstraintKind::Float => value.parse::<f64>().is_ok(),
+            ConstraintKind::Bool => value.parse::<bool>().is_ok(),
+            ConstraintKind::Pattern(regex) => regex.is_match(value),
+        }
+    }
+}
+
+//
+//  A constraint has to match the whole segment, not just part of it
+//
+fn whole_match(source: &str) -> Result<Regex, String> {
+    //
+    //  Checked on its own first, so a pattern like `a)|(b` can't break out of the group
+    //
+    Regex::new(source)
+        .and_then(|_| Regex::new(&format!("^(?:{})$", source)))
+        .map_err(|e| e.to_string())
+}
+
+//
+//  Longer values never match a constraint, whatever it is, which bounds the time a request
+//  can spend matching them
+//
+const MAX_CONSTRAINED_LEN: usize = 1024;
+
+//
+//  One `/`-separated piece of a route pattern
+//
+#[derive(Debug, Clone)]
+enum Segment {
+    Static(String),
+    Param {
+        name: String,
+        constraint: Option<Constraint>,
+        optional: bool,
+    },
+    //
+    //  `*`, any single segment
+    //
+    Wildcard,
+    //
+    //  `*name` or `:name*`, the rest of the path, only allowed last
+    //
+    CatchAll(String),
+}
 
 #[derive(Debug, Clone)]
 pub struct RouteMatcher {
-    path: String,
+    segments: Vec<Segment>,
 }
 
 impl RouteMatcher {
-    pub fn new(input: &str) -> Self {
-        Self {
-            path: input.to_owned(),
-        }
+    //
+    //  Parses the pattern once, up front, so a bad route fails at registration rather than
+    //  by never matching
+    //
+    pub fn new(input: &str) -> Result<Self, String> {
+        Self::parse(input).map(|segments| Self { segments })
     }
 
     pub fn matches(&self, input: &str) -> Option<HashMap<String, String>> {
-        if self.straight_match(input) {
-            Some(HashMap::<String, String>::new())
+        let mut map = HashMap::<String, String>::new();
+        if match_segments(&self.segments, &split(input), &mut map) {
+            Some(map)
         } else {
-            let mut route_lexer = Lexer::new(&self.path);
-            let mut sym_lexer = Lexer::new(input);
-            let mut map = HashMap::<String, String>::new();
-            loop {
-                let sym_t = sym_lexer.next_token();
-                let route_t = route_lexer.next_token();
-                match (route_t, sym_t) {
-                    (Token::Param(k), Token::Path(v)) => {
-                        map.insert(k, v);
-                    }
-                    (Token::Path(route), Token::Path(sym)) => {
-                        if route != sym {
-                            break None;
-                        } else {
-                            continue;
-                        }
-                    }
-                    (Token::Slash, Token::Slash) => continue,
-                    (Token::End, Token::End) => break Some(map),
-                    _ => break None,
-                }
+            None
+        }
+    }
+
+    //
+    //  Every pattern this one stands for once each optional param is either present or
+    //  left out, e.g. `/a/:b?` is both `/a/:b` and `/a`
+    //
+    fn expand(&self) -> Vec<Vec<Segment>> {
+        self.segments.iter().fold(
+            vec![Vec::<Segment>::new()],
+            |variants, segment| match segment {
+                Segment::Param {
+                    name,
+                    constraint,
+                    optional: true,
+                } => variants
+                    .into_iter()
+                    .flat_map(|variant| {
+                        let mut with = variant.clone();
+                        with.push(Segment::Param {
+                            name: name.clone(),
+                            constraint: constraint.clone(),
+                            optional: false,
+                        });
+                        [with, variant]
+                    })
+                    .collect(),
+                segment => variants
+                    .into_iter()
+                    .map(|mut variant| {
+                        variant.push(segment.clone());
+                        variant
+                    })
+                    .collect(),
+            },
+        )
+    }
+
+    fn parse(input: &str) -> Result<Vec<Segment>, String> {
+        let mut lexer = Lexer::new(input);
+        if lexer.next_token() != Token::Slash {
+            return Err("patterns have to start with '/'".to_string());
+        }
+
+        let mut segments = Vec::<Segment>::new();
+        let mut tokens = Vec::<Token>::new();
+        loop {
+            match lexer.next_token() {
+                Token::Slash => segments.push(Self::parse_segment(std::mem::take(&mut tokens))?),
+                Token::End => break,
+                Token::Illegal(e) => return Err(e),
+                token => tokens.push(token),
             }
         }
+        //
+        //  The root pattern `/` has no segments, any other ends with the one after its last slash
+        //
+        if !segments.is_empty() || !tokens.is_empty() {
+            segments.push(Self::parse_segment(tokens)?);
+        }
+
+        if let Some(pos) = segments
+            .iter()
+            .position(|segment| matches!(segment, Segment::CatchAll(_)))
+        {
+            if pos != segments.len() - 1 {
+                return Err("a catch-all has to be the last segment".to_string());
+            }
+        }
+        Ok(segments)
     }
 
-    fn straight_match(&self, input: &str) -> bool {
-        input == &self.path
+    fn parse_segment(tokens: Vec<Token>) -> Result<Segment, String> {
+        let param = |name: String, constraint: Option<String>, optional: bool| {
+            Ok(Segment::Param {
+                name,
+                constraint: constraint.as_deref().map(Constraint::new).transpose()?,
+                optional,
+            })
+        };
+        match tokens.as_slice() {
+            [] => Ok(Segment::Static(String::new())),
+            [Token::Path(path)] => Ok(Segment::Static(path.clone())),
+            [Token::Param(name, constraint)] => param(name.clone(), constraint.clone(), false),
+            [Token::Param(name, constraint), Token::QuestionMark] => {
+                param(name.clone(), constraint.clone(), true)
+            }
+            [Token::Param(name, None), Token::Asterisk] | [Token::Asterisk, Token::Path(name)] => {
+                Ok(Segment::CatchAll(name.clone()))
+            }
+            [Token::Asterisk] => Ok(Segment::Wildcard),
+            tokens => Err(format!("unsupported segment {:?}", tokens)),
+        }
+    }
+}
+
+//
+//  The segments of a path, `/` has none
+//
+fn split(path: &str) -> Vec<&str> {
+    match path.strip_prefix('/').unwrap_or(path) {
+        "" => Vec::<&str>::new(),
+        path => path.split('/').collect(),
+    }
+}
+
+//
+//  Match the pattern segments against the path segments, backtracking over optional params
+//
+fn match_segments(segments: &[Segment], parts: &[&str], map: &mut HashMap<String, String>) -> bool {
+    let Some((segment, rest)) = segments.split_first() else {
+        return parts.is_empty();
+    };
+    match segment {
+        Segment::Static(path) => {
+            parts.first() == Some(&path.as_str()) && match_segments(rest, &parts[1..], map)
+        }
+        Segment::Wildcard => {
+            parts.first().is_some_and(|part| !part.is_empty())
+                && match_segments(rest, &parts[1..], map)
+        }
+        Segment::CatchAll(name) => {
+            map.insert(name.clone(), parts.join("/"));
+            true
+        }
+        Segment::Param {
+            name,
+            constraint,
+            optional,
+        } => {
+            let captured = match parts.first() {
+                Some(part)
+                    if !part.is_empty() && constraint.as_ref().is_none_or(|c| c.allows(part)) =>
+                {
+                    map.insert(name.clone(), part.to_string());
+                    if match_segments(rest, &parts[1..], map) {
+                        return true;
+                    }
+                    map.remove(name);
+                    false
+                }
+                _ => false,
+            };
+            captured || (*optional && match_segments(rest, parts, map))
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::RouteMatcher;
+    use super::{RouteMatcher, RouteTree, MAX_CONSTRAINED_LEN};
 
     #[test]
     fn matches_without_params() {
-        let matcher = RouteMatcher::new("/user/14/profile");
+        let matcher = RouteMatcher::new("/user/14/profile").unwrap();
 
         if let Some(matches) = matcher.matches("/user/14/profile") {
             assert!(matches.is_empty());
         } else {
-            assert!(false);
+            panic!("route did not match");
         }
     }
 
     #[test]
     fn matches_params() {
-        let matcher = RouteMatcher::new("/:user/:id/profile");
+        let matcher = RouteMatcher::new("/:user/:id/profile").unwrap();
 
         if let Some(matches) = matcher.matches("/test_user/14/profile") {
             if let Some(user) = matches.get("user") {
                 assert_eq!(user, "test_user");
             } else {
-                assert!(false);
+                panic!("route did not match");
             };
             if let Some(user) = matches.get("id") {
                 assert_eq!(user, "14");
             } else {
-                assert!(false);
+                panic!("route did not match");
             };
         } else {
-            assert!(false);
+            panic!("route did not match");
+        }
+    }
+
+    #[test]
+    fn applies_param_constraints() {
+        let matcher = RouteMatcher::new(r"/user/:id(\d+)").unwrap();
+        assert_eq!(
+            matcher.matches("/user/42").unwrap().get("id").unwrap(),
+            "42"
+        );
+        assert!(matcher.matches("/user/abc").is_none());
+        assert!(matcher.matches("/user/42abc").is_none());
+
+        let matcher = RouteMatcher::new("/:slug<[a-z-]+>").unwrap();
+        assert!(matcher.matches("/my-post").is_some());
+        assert!(matcher.matches("/My_Post").is_none());
+
+        let matcher = RouteMatcher::new("/:ext<(?i)jpe?g|png>").unwrap();
+        assert!(matcher.matches("/JPG").is_some());
+        assert!(matcher.matches("/xpng").is_none());
+
+        let matcher = RouteMatcher::new("/page/:n<uint>/:ratio<float>").unwrap();
+        assert!(matcher.matches("/page/3/0.5").is_some());
+        assert!(matcher.matches("/page/-3/0.5").is_none());
+        assert!(matcher.matches("/page/3/half").is_none());
+    }
+
+    #[test]
+    fn caps_constrained_segment_length() {
+        let matcher = RouteMatcher::new(r"/user/:id((\d|-)+)").unwrap();
+        let id = "1".repeat(MAX_CONSTRAINED_LEN);
+        assert!(matcher.matches(&format!("/user/{}", id)).is_some());
+        let id = "1-".repeat(4075);
+        assert!(matcher.matches(&format!("/user/{}", id)).is_none());
+
+        let mut tree = RouteTree::<usize>::new();
+        tree.insert(&matcher, 1).unwrap();
+        tree.insert(&RouteMatcher::new("/user/:name").unwrap(), 2)
+            .unwrap();
+        assert_eq!(tree.find(&format!("/user/{}", id)).unwrap().0, &2);
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        let matcher = RouteMatcher::new("/files/*/meta").unwrap();
+        assert!(matcher.matches("/files/a.txt/meta").is_some());
+        assert!(matcher.matches("/files/a/b/meta").is_none());
+
+        let matcher = RouteMatcher::new("/static/*rest").unwrap();
+        let matches = matcher.matches("/static/css/site.css").unwrap();
+        assert_eq!(matches.get("rest").unwrap(), "css/site.css");
+        assert_eq!(matcher.matches("/static").unwrap().get("rest").unwrap(), "");
+
+        let matcher = RouteMatcher::new("/proxy/:path*").unwrap();
+        let matches = matcher.matches("/proxy/a/b/c").unwrap();
+        assert_eq!(matches.get("path").unwrap(), "a/b/c");
+        assert!(matcher.matches("/other/a").is_none());
+    }
+
+    #[test]
+    fn matches_optional_segments() {
+        let matcher = RouteMatcher::new("/user/:id?/edit").unwrap();
+        assert_eq!(
+            matcher.matches("/user/7/edit").unwrap().get("id").unwrap(),
+            "7"
+        );
+        assert!(matcher.matches("/user/edit").unwrap().is_empty());
+        assert!(matcher.matches("/user/7/8/edit").is_none());
+
+        let matcher = RouteMatcher::new("/posts/:page(\\d+)?").unwrap();
+        assert!(matcher.matches("/posts").unwrap().is_empty());
+        assert!(matcher.matches("/posts/2").is_some());
+        assert!(matcher.matches("/posts/two").is_none());
+    }
+
+    #[test]
+    fn matches_index_and_trailing_slash() {
+        let matcher = RouteMatcher::new("/").unwrap();
+        assert!(matcher.matches("/").is_some());
+        assert!(matcher.matches("/a").is_none());
+
+        let matcher = RouteMatcher::new("/users/").unwrap();
+        assert!(matcher.matches("/users/").is_some());
+        assert!(matcher.matches("/users").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert_eq!(
+            RouteMatcher::new("/*rest/edit").unwrap_err(),
+            "a catch-all has to be the last segment"
+        );
+        for pattern in [
+            "user",
+            "/user/:id([0-9)",
+            "/user/:id<int",
+            "/a/b:c",
+            "/user/:id<a)|(b>",
+        ] {
+            assert!(RouteMatcher::new(pattern).is_err(), "{}", pattern);
         }
     }
 }
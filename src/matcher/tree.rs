@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use super::{split, Constraint, RouteMatcher, Segment};
+
+//
+//  A prefix tree of route patterns, one level per path segment. A lookup walks the path
+//  once, trying at every level a static segment first, then params (constrained ones before
+//  unconstrained ones), then `*`, and finally a catch-all, and only backtracks when a
+//  branch it took has no route further down.
+//
+#[derive(Clone)]
+pub struct RouteTree<T> {
+    root: Node<T>,
+}
+
+#[derive(Clone)]
+struct Node<T> {
+    value: Option<T>,
+    statics: HashMap<String, Node<T>>,
+    params: Vec<ParamChild<T>>,
+    wildcard: Option<Box<Node<T>>>,
+    catch_all: Option<(String, T)>,
+}
+
+#[derive(Clone)]
+struct ParamChild<T> {
+    name: String,
+    constraint: Option<Constraint>,
+    node: Node<T>,
+}
+
+impl<T: Clone> RouteTree<T> {
+    pub fn new() -> Self {
+        Self { root: Node::new() }
+    }
+
+    //
+    //  Fails when the pattern overlaps a route that is already in the tree so that a path
+    //  could not tell them apart, e.g. `/user/:id` and `/user/:name`. The tree is left as it
+    //  was when it fails, even if some variants of an optional pattern did fit.
+    //
+    pub fn insert(&mut self, matcher: &RouteMatcher, value: T) -> Result<(), String> {
+        let mut root = self.root.clone();
+        for segments in matcher.expand() {
+            root.insert(&segments, value.clone())?;
+        }
+        self.root = root;
+        Ok(())
+    }
+
+    pub fn find(&self, path: &str) -> Option<(&T, HashMap<String, String>)> {
+        let mut params = HashMap::<String, String>::new();
+        self.root
+            .find(&split(path), &mut params)
+            .map(|value| (value, params))
+    }
+}
+
+impl<T: Clone> Default for RouteTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Self {
+            value: None,
+            statics: HashMap::<String, Node<T>>::new(),
+            params: Vec::<ParamChild<T>>::new(),
+            wildcard: None,
+            catch_all: None,
+        }
+    }
+
+    fn insert(&mut self, segments: &[Segment], value: T) -> Result<(), String> {
+        let Some((segment, rest)) = segments.split_first() else {
+            if self.value.is_some() {
+                return Err("a route with the same pattern is already registered".to_string());
+            }
+            self.value = Some(value);
+            return Ok(());
+        };
+        match segment {
+            Segment::Static(path) => self
+                .statics
+                .entry(path.clone())
+                .or_insert_with(Node::new)
+                .insert(rest, value),
+            Segment::Wildcard => self
+                .wildcard
+                .get_or_insert_with(|| Box::new(Node::new()))
+                .insert(rest, value),
+            Segment::CatchAll(name) => match &self.catch_all {
+                Some((existing, _)) => Err(format!(
+                    "catch-all '*{}' conflicts with '*{}'",
+                    name, existing
+                )),
+                None => {
+                    self.catch_all = Some((name.clone(), value));
+                    Ok(())
+                }
+            },
+            Segment::Param {
+                name, constraint, ..
+            } => {
+                let source = constraint.as_ref().map(|c| c.source.as_str());
+                let pos = match self
+                    .params
+                    .iter()
+                    .position(|p| p.constraint.as_ref().map(|c| c.source.as_str()) == source)
+                {
+                    Some(pos) if self.params[pos].name != *name => {
+                        return Err(format!(
+                            "param ':{}' conflicts with ':{}'",
+                            name, self.params[pos].name
+                        ))
+                    }
+                    Some(pos) => pos,
+                    None => {
+                        let pos = match constraint {
+                            Some(_) => self
+                                .params
+                                .iter()
+                                .position(|p| p.constraint.is_none())
+                                .unwrap_or(self.params.len()),
+                            None => self.params.len(),
+                        };
+                        self.params.insert(
+                            pos,
+                            ParamChild {
+                                name: name.clone(),
+                                constraint: constraint.clone(),
+                                node: Node::new(),
+                            },
+                        );
+                        pos
+                    }
+                };
+                self.params[pos].node.insert(rest, value)
+            }
+        }
+    }
+
+    //
+    //  Params are only recorded once the branch they're on has matched, so a branch that's
+    //  backed out of leaves nothing behind
+    //
+    fn find(&self, parts: &[&str], params: &mut HashMap<String, String>) -> Option<&T> {
+        let Some((part, rest)) = parts.split_first() else {
+            return self
+                .value
+                .as_ref()
+                .or_else(|| self.catch_all(parts, params));
+        };
+        if let Some(value) = self
+            .statics
+            .get(*part)
+            .and_then(|node| node.find(rest, params))
+        {
+            return Some(value);
+        }
+        if !part.is_empty() {
+            for param in &self.params {
+                if !param.constraint.as_ref().is_none_or(|c| c.allows(part)) {
+                    continue;
+                }
+                if let Some(value) = param.node.find(rest, params) {
+                    params.insert(param.name.clone(), part.to_string());
+                    return Some(value);
+                }
+            }
+            if let Some(value) = self
+                .wildcard
+                .as_ref()
+                .and_then(|node| node.find(rest, params))
+            {
+                return Some(value);
+            }
+        }
+        self.catch_all(parts, params)
+    }
+
+    fn catch_all(&self, parts: &[&str], params: &mut HashMap<String, String>) -> Option<&T> {
+        self.catch_all.as_ref().map(|(name, value)| {
+            params.insert(name.clone(), parts.join("/"));
+            value
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RouteTree;
+    use crate::matcher::RouteMatcher;
+
+    fn tree(patterns: &[&'static str]) -> RouteTree<&'static str> {
+        let mut tree = RouteTree::<&'static str>::new();
+        for pattern in patterns {
+            tree.insert(&RouteMatcher::new(pattern).unwrap(), pattern)
+                .unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn prefers_static_then_params_then_wildcards() {
+        let tree = tree(&[
+            "/files/*rest",
+            "/user/*",
+            "/user/:name",
+            "/user/:id<uint>",
+            "/user/me",
+            "/user/:name/posts",
+        ]);
+        let find = |path| tree.find(path).map(|(pattern, _)| *pattern);
+
+        assert_eq!(find("/user/me"), Some("/user/me"));
+        assert_eq!(find("/user/42"), Some("/user/:id<uint>"));
+        assert_eq!(find("/user/bob"), Some("/user/:name"));
+        assert_eq!(find("/user/me/posts"), Some("/user/:name/posts"));
+        assert_eq!(find("/files/a/b"), Some("/files/*rest"));
+        assert_eq!(find("/user"), None);
+
+        let (_, params) = tree.find("/user/me/posts").unwrap();
+        assert_eq!(params.get("name").unwrap(), "me");
+        let (_, params) = tree.find("/user/42").unwrap();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params.get("id").unwrap(), "42");
+    }
+
+    #[test]
+    fn backtracks_out_of_dead_ends() {
+        let tree = tree(&["/a/b/c", "/a/:x/d", "/a/:x?/e", "/*rest"]);
+        let find = |path| tree.find(path).map(|(pattern, _)| *pattern);
+
+        assert_eq!(find("/a/b/d"), Some("/a/:x/d"));
+        assert_eq!(find("/a/e"), Some("/a/:x?/e"));
+        assert_eq!(find("/a/b/f"), Some("/*rest"));
+        assert_eq!(tree.find("/a/b/f").unwrap().1.get("rest").unwrap(), "a/b/f");
+        assert!(!tree.find("/a/b/d").unwrap().1.contains_key("rest"));
+    }
+
+    #[test]
+    fn rejects_conflicting_routes() {
+        let mut tree = tree(&["/user/:id", "/post/:id?", "/static/*rest"]);
+        for pattern in ["/user/:id", "/user/:name", "/post", "/static/*path"] {
+            assert!(
+                tree.insert(&RouteMatcher::new(pattern).unwrap(), pattern)
+                    .is_err(),
+                "{}",
+                pattern
+            );
+        }
+        assert!(tree
+            .insert(&RouteMatcher::new("/user/:id/edit").unwrap(), "")
+            .is_ok());
+
+        let mut posts = self::tree(&["/post"]);
+        assert!(posts
+            .insert(&RouteMatcher::new("/post/:id?").unwrap(), "/post/:id?")
+            .is_err());
+        assert!(posts.find("/post/7").is_none());
+        assert_eq!(posts.find("/post").unwrap().0, &"/post");
+    }
+}
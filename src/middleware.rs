@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use crate::{request::Request, response::Response, router::RouteHandler};
+
+pub type Middleware = Arc<dyn Fn(&mut Request, &mut Response, Next) + Send + Sync + 'static>;
+
+//
+//  The remainder of a middleware chain, ending in the matched route handler.
+//  A middleware continues the chain by calling `next.run(req, res)`, or
+//  short-circuits it by returning without doing so.
+//
+pub struct Next<'a> {
+    middleware: &'a [Middleware],
+    handler: &'a RouteHandler,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(middleware: &'a [Middleware], handler: &'a RouteHandler) -> Self {
+        Self {
+            middleware,
+            handler,
+        }
+    }
+
+    pub fn run(self, req: &mut Request, res: &mut Response) {
+        if let Some((current, rest)) = self.middleware.split_first() {
+            current(req, res, Next::new(rest, self.handler));
+        } else {
+            (self.handler)(req, res);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{Middleware, Next};
+    use crate::{request::Request, response::Response, router::RouteHandler};
+
+    fn request() -> Request {
+        Request::parse(b"GET / HTTP/1.1\r\n\r\n").unwrap()
+    }
+
+    fn first(req: &mut Request, res: &mut Response, next: Next) {
+        res.set_header("X-First", "1");
+        next.run(req, res);
+    }
+
+    fn guard(_: &mut Request, res: &mut Response, _: Next) {
+        res.set_status(401);
+    }
+
+    fn handler(_: &Request, res: &mut Response) {
+        res.set_status(200);
+        res.set_body("handled");
+    }
+
+    #[test]
+    fn runs_middleware_then_handler() {
+        let chain: [Middleware; 1] = [Arc::new(first)];
+        let handler: RouteHandler = Arc::new(handler);
+        let mut req = request();
+        let mut res = Response::new();
+        res.set_status(0);
+        Next::new(&chain, &handler).run(&mut req, &mut res);
+
+        let built = String::from_utf8(res.build_response()).unwrap();
+        assert!(built.starts_with("HTTP/1.1 200"));
+        assert!(built.contains("X-First: 1"));
+        assert!(built.contains("handled"));
+    }
+
+    #[test]
+    fn middleware_can_short_circuit() {
+        let chain: [Middleware; 2] = [Arc::new(first), Arc::new(guard)];
+        let handler: RouteHandler = Arc::new(handler);
+        let mut req = request();
+        let mut res = Response::new();
+        Next::new(&chain, &handler).run(&mut req, &mut res);
+
+        let built = String::from_utf8(res.build_response()).unwrap();
+        assert!(built.starts_with("HTTP/1.1 401"));
+        assert!(built.contains("X-First: 1"));
+        assert!(!built.contains("handled"));
+    }
+}
@@ -0,0 +1,378 @@
+use std::io::Read;
+
+use crate::request::Request;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_header_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    HeaderTooLarge,
+    BodyTooLarge,
+    Malformed(String),
+    Io(std::io::Error),
+}
+
+impl RequestError {
+    //
+    //  The status code sent back to the client, if the connection can still be answered
+    //
+    pub fn status(&self) -> Option<usize> {
+        match self {
+            Self::HeaderTooLarge => Some(431),
+            Self::BodyTooLarge => Some(413),
+            Self::Malformed(_) => Some(400),
+            Self::Io(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HeaderTooLarge => write!(f, "request header fields too large"),
+            Self::BodyTooLarge => write!(f, "request body too large"),
+            Self::Malformed(details) => write!(f, "malformed request: {}", details),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for RequestError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+//
+//  Reads requests off of a stream: the headers up to the first empty line, then either a
+//  chunked body or exactly Content-Length bytes of body. Bytes read past the end of a
+//  request are kept for the next one.
+//
+pub struct RequestReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    limits: Limits,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(inner: R, limits: Limits) -> Self {
+        Self {
+            inner,
+            buf: Vec::<u8>::new(),
+            limits,
+        }
+    }
+
+    //
+    //  Wait for the first bytes of the next request, returning false if the stream closes
+    //  first. Lets a server time out an idle connection differently from a slow request.
+    //
+    pub fn wait_for_request(&mut self) -> std::io::Result<bool> {
+        Ok(!self.buf.is_empty() || self.fill()? > 0)
+    }
+
+    //
+    //  Returns `Ok(None)` when the stream closes cleanly before a new request starts
+    //
+    pub fn read_request(&mut self) -> Result<Option<Request>, RequestError> {
+        let head_end = loop {
+            if let Some(pos) = find(&self.buf, b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if self.buf.len() > self.limits.max_header_size {
+                return Err(RequestError::HeaderTooLarge);
+            }
+            if self.fill()? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(RequestError::Malformed(
+                    "connection closed before the end of the headers".to_string(),
+                ));
+            }
+        };
+        if head_end > self.limits.max_header_size {
+            return Err(RequestError::HeaderTooLarge);
+        }
+
+        let head: Vec<u8> = self.buf.drain(..head_end).collect();
+        let mut req = Request::parse(&head)?;
+
+        //
+        //  A request with both Transfer-Encoding and Content-Length is rejected rather than
+        //  letting one override the other, since a proxy might have picked the other one.
+        //  Chunked has to be the final coding.
+        //
+        let encodings = req.get_headers("Transfer-Encoding");
+        let length = req.content_length()?;
+        let body = if let Some(encoding) = encodings.last() {
+            if length.is_some() {
+                return Err(RequestError::Malformed(
+                    "both Transfer-Encoding and Content-Length".to_string(),
+                ));
+            }
+            let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+            if !last.eq_ignore_ascii_case("chunked") {
+                return Err(RequestError::Malformed(
+                    "unsupported Transfer-Encoding".to_string(),
+                ));
+            }
+            self.read_chunked_body()?
+        } else {
+            let length = length.unwrap_or(0);
+            if length > self.limits.max_body_size {
+                return Err(RequestError::BodyTooLarge);
+            }
+            self.fill_to(length)?;
+            self.buf.drain(..length).collect()
+        };
+        req.set_body(body);
+
+        Ok(Some(req))
+    }
+
+    fn read_chunked_body(&mut self) -> Result<Vec<u8>, RequestError> {
+        let mut body = Vec::<u8>::new();
+        loop {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(RequestError::Malformed("invalid chunk size".to_string()));
+            }
+            //
+            //  Too many digits for a usize is too large a chunk too
+            //
+            let size = usize::from_str_radix(size, 16).map_err(|_| RequestError::BodyTooLarge)?;
+
+            if size == 0 {
+                //
+                //  Trailer fields are read and discarded up to the final empty line
+                //
+                while !self.read_line()?.is_empty() {}
+                break Ok(body);
+            }
+            if size > self.limits.max_body_size - body.len() {
+                return Err(RequestError::BodyTooLarge);
+            }
+
+            self.fill_to(size + 2)?;
+            body.extend(self.buf.drain(..size));
+            if self.buf.drain(..2).as_slice() != b"\r\n" {
+                return Err(RequestError::Malformed(
+                    "chunk is longer than its size".to_string(),
+                ));
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, RequestError> {
+        loop {
+            if let Some(pos) = find(&self.buf, b"\r\n") {
+                let line: Vec<u8> = self.buf.drain(..pos + 2).take(pos).collect();
+                break Ok(String::from_utf8_lossy(&line).to_string());
+            }
+            if self.buf.len() > self.limits.max_header_size {
+                return Err(RequestError::Malformed("chunk line too long".to_string()));
+            }
+            if self.fill()? == 0 {
+                return Err(RequestError::Malformed(
+                    "connection closed before the end of the body".to_string(),
+                ));
+            }
+        }
+    }
+
+    fn fill_to(&mut self, len: usize) -> Result<(), RequestError> {
+        while self.buf.len() < len {
+            if self.fill()? == 0 {
+                return Err(RequestError::Malformed(
+                    "connection closed before the end of the body".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> std::io::Result<usize> {
+        let mut chunk = [0; 4096];
+        let read = self.inner.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..read]);
+        Ok(read)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::{Limits, RequestError, RequestReader};
+
+    //
+    //  Hands out the input a few bytes at a time, like a request split across TCP segments
+    //
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.step.min(self.data.len()).min(buf.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn reads_body_split_across_reads() {
+        let body = "x".repeat(3000);
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let data = Trickle {
+            data: raw.as_bytes(),
+            step: 7,
+        };
+        let mut reader = RequestReader::new(data, Limits::default());
+
+        let req = reader.read_request().unwrap().unwrap();
+        assert_eq!(req.route(), "/upload");
+        assert_eq!(req.body(), body.as_bytes());
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_bytes_of_following_request() {
+        let raw = b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /b HTTP/1.1\r\n\r\n";
+        let mut reader = RequestReader::new(&raw[..], Limits::default());
+
+        assert!(reader.wait_for_request().unwrap());
+        let first = reader.read_request().unwrap().unwrap();
+        assert_eq!(first.body(), b"hi");
+        assert!(reader.wait_for_request().unwrap());
+        let second = reader.read_request().unwrap().unwrap();
+        assert_eq!(second.route(), "/b");
+        assert!(second.body().is_empty());
+        assert!(!reader.wait_for_request().unwrap());
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n\
+            GET /next HTTP/1.1\r\n\r\n";
+        let data = Trickle {
+            data: &raw[..],
+            step: 3,
+        };
+        let mut reader = RequestReader::new(data, Limits::default());
+
+        let req = reader.read_request().unwrap().unwrap();
+        assert_eq!(req.body(), b"hello, world");
+        let next = reader.read_request().unwrap().unwrap();
+        assert_eq!(next.route(), "/next");
+    }
+
+    #[test]
+    fn rejects_bad_chunks() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        let mut reader = RequestReader::new(&raw[..], Limits::default());
+        assert!(matches!(
+            reader.read_request(),
+            Err(RequestError::Malformed(_))
+        ));
+
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+        let mut reader = RequestReader::new(&raw[..], Limits::default());
+        assert!(matches!(
+            reader.read_request(),
+            Err(RequestError::Malformed(_))
+        ));
+
+        for size in ["ffffffffffffffff", "1ffffffffffffffff"] {
+            let raw = format!(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n{}\r\n",
+                size
+            );
+            let mut reader = RequestReader::new(raw.as_bytes(), Limits::default());
+            assert!(matches!(
+                reader.read_request(),
+                Err(RequestError::BodyTooLarge)
+            ));
+        }
+
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+1\r\na\r\n0\r\n\r\n";
+        let mut reader = RequestReader::new(&raw[..], Limits::default());
+        assert!(matches!(
+            reader.read_request(),
+            Err(RequestError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_ambiguous_body_framing() {
+        for head in [
+            "Transfer-Encoding: chunked\r\nContent-Length: 5",
+            "Content-Length: 5\r\nTransfer-Encoding: chunked",
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip",
+            "Content-Length: +5",
+            "Content-Length: 5\r\nContent-Length: 6",
+            "Content-Length: 5, 6",
+            "Content-Length: ",
+        ] {
+            let raw = format!("POST / HTTP/1.1\r\n{}\r\n\r\n0\r\n\r\nhello", head);
+            let mut reader = RequestReader::new(raw.as_bytes(), Limits::default());
+            assert!(
+                matches!(reader.read_request(), Err(RequestError::Malformed(_))),
+                "{}",
+                head
+            );
+        }
+
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\nhello";
+        let mut reader = RequestReader::new(raw.as_bytes(), Limits::default());
+        assert_eq!(reader.read_request().unwrap().unwrap().body(), b"hello");
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = Limits {
+            max_header_size: 64,
+            max_body_size: 4,
+        };
+
+        let raw = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(100));
+        let mut reader = RequestReader::new(raw.as_bytes(), limits);
+        assert!(matches!(
+            reader.read_request(),
+            Err(RequestError::HeaderTooLarge)
+        ));
+
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let mut reader = RequestReader::new(&raw[..], limits);
+        assert!(matches!(
+            reader.read_request(),
+            Err(RequestError::BodyTooLarge)
+        ));
+    }
+}
@@ -1,26 +1,66 @@
-use std::{collections::HashMap, fmt::Display, str::Lines};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    str::{FromStr, Lines},
+    sync::Arc,
+};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+use crate::{
+    extensions::Extensions,
+    extract::{self, FromParams, ParamError, Params},
+    header::{HeaderError, HeaderMap},
+    reader::RequestError,
+    urlencoded,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum HttpMethod {
     Get,
+    Head,
     Post,
     Put,
     Patch,
     Delete,
     Options,
-    Error,
+    Connect,
+    Trace,
+    //
+    //  Any other method, e.g. WebDAV's PROPFIND, as it was sent
+    //
+    Other(String),
 }
 
 impl HttpMethod {
     pub fn from(method: &str) -> Self {
         match method.to_lowercase().as_str() {
             "get" => Self::Get,
+            "head" => Self::Head,
             "post" => Self::Post,
             "put" => Self::Put,
             "patch" => Self::Patch,
             "delete" => Self::Delete,
             "options" => Self::Options,
-            _ => Self::Error,
+            "connect" => Self::Connect,
+            "trace" => Self::Trace,
+            _ => Self::Other(method.to_string()),
+        }
+    }
+
+    //
+    //  The method as it's written on the wire, e.g. in an `Allow` header
+    //
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Patch => "PATCH",
+            Self::Delete => "DELETE",
+            Self::Options => "OPTIONS",
+            Self::Connect => "CONNECT",
+            Self::Trace => "TRACE",
+            Self::Other(method) => method,
         }
     }
 }
@@ -29,80 +69,176 @@ impl Display for HttpMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Get => f.write_str("Get"),
+            Self::Head => f.write_str("Head"),
             Self::Post => f.write_str("Post"),
             Self::Put => f.write_str("Put"),
             Self::Patch => f.write_str("Patch"),
             Self::Delete => f.write_str("Delete"),
             Self::Options => f.write_str("Options"),
-            Self::Error => f.write_str("_"),
+            Self::Connect => f.write_str("Connect"),
+            Self::Trace => f.write_str("Trace"),
+            Self::Other(method) => f.write_str(method),
         }
     }
 }
 
 struct RequestData {
-    pub headers: HashMap<String, String>,
-    pub query_params: HashMap<String, String>,
+    pub headers: HeaderMap,
+    pub query_params: HashMap<String, Vec<String>>,
     pub body: Vec<u8>,
     pub method: HttpMethod,
+    pub target: String,
     pub route: String,
+    pub version: String,
 }
 
 impl RequestData {
     pub fn new(
-        headers: HashMap<String, String>,
-        query_params: HashMap<String, String>,
+        headers: HeaderMap,
+        query_params: HashMap<String, Vec<String>>,
         body: Vec<u8>,
         method: HttpMethod,
+        target: String,
         route: String,
+        version: String,
     ) -> Self {
         Self {
             headers,
             query_params,
             body,
             method,
+            target,
             route,
+            version,
         }
     }
 }
 
 pub struct Request {
     method: HttpMethod,
+    target: String,
     route: String,
-    headers: HashMap<String, String>,
+    version: String,
+    headers: HeaderMap,
     url_params: HashMap<String, String>,
-    query_params: HashMap<String, String>,
+    query_params: HashMap<String, Vec<String>>,
     body: Vec<u8>,
+    state: Arc<Extensions>,
+    extensions: Extensions,
 }
 
 impl Request {
-    pub fn new(buffer: [u8; 1024]) -> Self {
-        let data = Self::parse_request(buffer);
-        Self {
+    //
+    //  Parse a raw request; everything after the blank line ending the headers is the body
+    //
+    pub fn parse(buffer: &[u8]) -> Result<Self, RequestError> {
+        let data = Self::parse_request(buffer)?;
+        Ok(Self {
             headers: data.headers,
             body: data.body,
             method: data.method,
+            target: data.target,
             route: data.route,
+            version: data.version,
             query_params: data.query_params,
             url_params: HashMap::<String, String>::new(),
-        }
+            state: Arc::new(Extensions::new()),
+            extensions: Extensions::new(),
+        })
     }
 
+    //
+    //  Header names are case-insensitive, the first value is returned for repeated headers
+    //
     pub fn get_header(&self, k: &str) -> Option<&String> {
         self.headers.get(k)
     }
 
+    pub fn get_headers(&self, k: &str) -> Vec<&String> {
+        self.headers.get_all(k)
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
     pub fn body(&self) -> &[u8] {
         &self.body
     }
 
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+
+    //
+    //  The body length from every Content-Length header, `Ok(None)` without one. Only plain
+    //  digits are accepted and repeated values have to agree, anything else could frame the
+    //  body differently than a proxy in front of the server did.
+    //
+    pub fn content_length(&self) -> Result<Option<usize>, RequestError> {
+        let invalid = || RequestError::Malformed("invalid Content-Length".to_string());
+        let mut length = None;
+        for value in self.get_headers("Content-Length") {
+            for part in value.split(',').map(str::trim) {
+                if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+                let part = part.parse::<usize>().map_err(|_| invalid())?;
+                if length.is_some_and(|length| length != part) {
+                    return Err(RequestError::Malformed(
+                        "conflicting Content-Length".to_string(),
+                    ));
+                }
+                length = Some(part);
+            }
+        }
+        Ok(length)
+    }
+
+    //
+    //  The path of the request target, without the query string
+    //
     pub fn route(&self) -> &String {
         &self.route
     }
 
+    //
+    //  The request target as sent, including any query string
+    //
+    pub fn target(&self) -> &String {
+        &self.target
+    }
+
     pub fn method(&self) -> &HttpMethod {
         &self.method
     }
 
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    //
+    //  Whether the client wants the connection kept open after this request: an explicit
+    //  Connection header wins, otherwise HTTP/1.1 defaults to open and older versions to closed
+    //
+    pub fn keep_alive(&self) -> bool {
+        let tokens = self
+            .get_header("Connection")
+            .map(|v| {
+                v.split(',')
+                    .map(|t| t.trim().to_lowercase())
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+        if tokens.iter().any(|t| t == "close") {
+            false
+        } else if tokens.iter().any(|t| t == "keep-alive") {
+            true
+        } else {
+            self.version == "HTTP/1.1"
+        }
+    }
+
     pub fn set_url_params(&mut self, params: Option<HashMap<String, String>>) {
         if let Some(params) = params {
             self.url_params = params;
@@ -113,75 +249,289 @@ impl Request {
         self.url_params.get(param)
     }
 
-    pub fn set_query_params(&mut self, params: Option<HashMap<String, String>>) {
+    //
+    //  A route parameter parsed into `T`, e.g. `req.param::<u64>("user_id")`
+    //
+    pub fn param<T>(&self, name: &str) -> Result<T, ParamError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.url_params.get(name) {
+            Some(value) => extract::parse_value("path", name, value),
+            None => Err(ParamError::Missing {
+                kind: "path",
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    pub fn query<T: FromParams>(&self) -> Result<T, ParamError> {
+        T::from_params(&Params::new("query", &self.query_params))
+    }
+
+    //
+    //  An application/x-www-form-urlencoded body read into `T`
+    //
+    pub fn form<T: FromParams>(&self) -> Result<T, ParamError> {
+        let content_type = self
+            .get_header("Content-Type")
+            .map(|v| v.as_str())
+            .unwrap_or_default();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if !media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return Err(ParamError::UnsupportedMediaType(content_type.to_string()));
+        }
+        let form = Self::parse_query_params(&String::from_utf8_lossy(&self.body));
+        T::from_params(&Params::new("form", &form))
+    }
+
+    pub fn set_query_params(&mut self, params: Option<HashMap<String, Vec<String>>>) {
         if let Some(params) = params {
             self.query_params = params;
         }
     }
 
+    //
+    //  The first value of a query parameter, see `query_all` for repeated keys
+    //
     pub fn get_query_params(&self, param: &str) -> Option<&String> {
-        self.query_params.get(param)
+        self.query_params
+            .get(param)
+            .and_then(|values| values.first())
+    }
+
+    pub fn query_all(&self, param: &str) -> &[String] {
+        self.query_params
+            .get(param)
+            .map(|values| values.as_slice())
+            .unwrap_or_default()
     }
 
-    fn parse_request(buffer: [u8; 1024]) -> RequestData {
-        let string = String::from_utf8_lossy(&buffer[..]);
+    pub fn set_state(&mut self, state: Arc<Extensions>) {
+        self.state = state;
+    }
 
-        let mut lines = string.lines();
-        let status_line = if let Some(line) = lines.next() {
-            line
-        } else {
-            ""
+    //
+    //  Application-wide state registered with `App::with_state`
+    //
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.state.get::<T>()
+    }
+
+    //
+    //  Per-request data, e.g. values attached by middleware for later handlers
+    //
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    fn parse_request(buffer: &[u8]) -> Result<RequestData, RequestError> {
+        let (head, body) = match buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => (&buffer[..pos + 2], &buffer[pos + 4..]),
+            None => (buffer, &[][..]),
         };
+        let string = String::from_utf8_lossy(head);
 
-        let headers = Self::parse_headers(&mut lines);
-        let (method, route) = Self::parse_status_line(status_line);
-        let query_params = Self::parse_query_params(&route);
-        let body = lines.collect::<Vec<&str>>().join("\n").as_bytes().to_vec();
-        RequestData::new(headers, query_params, body, method, route)
+        let mut lines = string.lines();
+        let status_line = lines.next().unwrap_or_default();
+
+        let (method, target, version) = Self::parse_status_line(status_line)?;
+        let headers = Self::parse_headers(&mut lines)?;
+        let (route, query) = target.split_once('?').unwrap_or((&target, ""));
+        let route = route.to_string();
+        let query_params = Self::parse_query_params(query);
+        Ok(RequestData::new(
+            headers,
+            query_params,
+            body.to_vec(),
+            method,
+            target,
+            route,
+            version,
+        ))
     }
 
-    fn parse_status_line(line: &str) -> (HttpMethod, String) {
-        let split: Vec<&str> = line.split_whitespace().collect();
-        if let (Some(method), Some(route)) = (split.get(0), split.get(1)) {
-            (HttpMethod::from(method), route.to_string())
-        } else {
-            (HttpMethod::Get, "/".to_string())
+    fn parse_status_line(line: &str) -> Result<(HttpMethod, String, String), RequestError> {
+        let split: Vec<&str> = line.split(' ').collect();
+        match split[..] {
+            [method, route, version]
+                if !method.is_empty() && !route.is_empty() && version.starts_with("HTTP/1.") =>
+            {
+                Ok((
+                    HttpMethod::from(method),
+                    route.to_string(),
+                    version.to_string(),
+                ))
+            }
+            _ => Err(RequestError::Malformed("invalid request line".to_string())),
         }
     }
 
-    fn parse_query_params(route: &str) -> HashMap<String, String> {
-        let split = route.split('?').collect::<Vec<&str>>();
-        if split.len() < 2 {
-            HashMap::<String, String>::new()
-        } else {
-            let params_string = split[1];
-            let params =
-                params_string
-                    .split('&')
-                    .fold(HashMap::<String, String>::new(), |mut acc, cur| {
-                        let split = cur.split(':').collect::<Vec<&str>>();
-                        if let (Some(k), Some(v)) = (split.first(), split.last()) {
-                            acc.insert(k.to_string(), v.to_string());
-                        }
-                        acc
-                    });
-            params
-        }
+    fn parse_query_params(query: &str) -> HashMap<String, Vec<String>> {
+        urlencoded::parse(query).into_iter().fold(
+            HashMap::<String, Vec<String>>::new(),
+            |mut acc, (k, v)| {
+                acc.entry(k).or_default().push(v);
+                acc
+            },
+        )
     }
 
-    fn parse_headers(lines: &mut Lines) -> HashMap<String, String> {
-        let mut headers = HashMap::<String, String>::new();
+    //
+    //  Header fields per RFC 9112: the name runs up to the first colon with no whitespace
+    //  before it, the value is trimmed of surrounding whitespace, and obs-fold continuation
+    //  lines are joined onto the previous value with a single space
+    //
+    fn parse_headers(lines: &mut Lines) -> Result<HeaderMap, RequestError> {
+        let mut fields = Vec::<(String, String)>::new();
 
-        while let Some(line) = &lines.next() {
-            if line == &"" {
+        for line in lines.by_ref() {
+            if line.is_empty() {
                 break;
-            } else {
-                let split: Vec<&str> = line.split(":").map(|s| s.trim_end().trim_start()).collect();
-                if let (Some(key), Some(value)) = (split.first(), split.last()) {
-                    headers.insert(key.to_string(), value.to_string());
+            }
+            if line.starts_with([' ', '\t']) {
+                match fields.last_mut() {
+                    Some((_, value)) => {
+                        let folded = line.trim_matches([' ', '\t']);
+                        if !value.is_empty() && !folded.is_empty() {
+                            value.push(' ');
+                        }
+                        value.push_str(folded);
+                    }
+                    None => {
+                        return Err(RequestError::Malformed(
+                            "whitespace before the first header field".to_string(),
+                        ))
+                    }
                 }
+                continue;
             }
+
+            let (name, value) = line.split_once(':').ok_or_else(|| {
+                RequestError::Malformed("header field without a colon".to_string())
+            })?;
+            if name.ends_with([' ', '\t']) {
+                return Err(RequestError::Malformed(
+                    "whitespace between header field name and colon".to_string(),
+                ));
+            }
+            fields.push((
+                name.to_string(),
+                value.trim_matches([' ', '\t']).to_string(),
+            ));
         }
-        headers
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in fields {
+            headers.append(&name, &value).map_err(|e| match e {
+                HeaderError::InvalidName(_) => {
+                    RequestError::Malformed("invalid header field name".to_string())
+                }
+                HeaderError::InvalidValue(_) => {
+                    RequestError::Malformed("invalid header field value".to_string())
+                }
+            })?;
+        }
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HttpMethod, Request};
+    use crate::reader::RequestError;
+
+    #[test]
+    fn headers_are_case_insensitive_and_repeatable() {
+        let req = Request::parse(
+            b"GET / HTTP/1.1\r\nContent-Type: text/plain\r\nAccept: a\r\nAccept: b\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(req.get_header("content-type").unwrap(), "text/plain");
+        assert_eq!(req.get_headers("ACCEPT"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn splits_header_on_first_colon() {
+        let req =
+            Request::parse(b"GET / HTTP/1.1\r\nHost:  localhost:3000 \r\nX-Time: 12:30:00\r\n\r\n")
+                .unwrap();
+        assert_eq!(req.get_header("Host").unwrap(), "localhost:3000");
+        assert_eq!(req.get_header("X-Time").unwrap(), "12:30:00");
+    }
+
+    #[test]
+    fn joins_obs_fold_lines() {
+        let req = Request::parse(b"GET / HTTP/1.1\r\nX-Long: first\r\n \t second\r\n\r\n").unwrap();
+        assert_eq!(req.get_header("X-Long").unwrap(), "first second");
+    }
+
+    #[test]
+    fn rejects_malformed_header_lines() {
+        for raw in [
+            &b"GET / HTTP/1.1\r\nno colon here\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\n folded: first\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nX-Nul: a\0b\r\n\r\n"[..],
+            &b"GET /\r\n\r\n"[..],
+        ] {
+            assert!(matches!(
+                Request::parse(raw),
+                Err(RequestError::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parses_query_string() {
+        let req =
+            Request::parse(b"GET /users?tag=a&tag=b%20c&name=J+Doe&id=1 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.route(), "/users");
+        assert_eq!(req.target(), "/users?tag=a&tag=b%20c&name=J+Doe&id=1");
+        assert_eq!(req.query_all("tag"), ["a", "b c"]);
+        assert_eq!(req.get_query_params("name").unwrap(), "J Doe");
+        assert_eq!(req.get_query_params("id").unwrap(), "1");
+        assert!(req.query_all("missing").is_empty());
+    }
+
+    #[test]
+    fn keep_alive_defaults_by_version() {
+        assert!(Request::parse(b"GET / HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .keep_alive());
+        assert!(!Request::parse(b"GET / HTTP/1.0\r\n\r\n")
+            .unwrap()
+            .keep_alive());
+        assert!(
+            !Request::parse(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap()
+                .keep_alive()
+        );
+        assert!(
+            Request::parse(b"GET / HTTP/1.0\r\nconnection: Keep-Alive\r\n\r\n")
+                .unwrap()
+                .keep_alive()
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_methods() {
+        let method = |raw: &[u8]| Request::parse(raw).unwrap().method().clone();
+        assert_eq!(method(b"HEAD / HTTP/1.1\r\n\r\n"), HttpMethod::Head);
+        assert_eq!(method(b"TRACE / HTTP/1.1\r\n\r\n"), HttpMethod::Trace);
+        assert_eq!(
+            method(b"PROPFIND / HTTP/1.1\r\n\r\n"),
+            HttpMethod::Other("PROPFIND".to_string())
+        );
+        assert_eq!(
+            HttpMethod::Other("PROPFIND".to_string()).as_str(),
+            "PROPFIND"
+        );
     }
 }
@@ -1,41 +1,548 @@
-use std::collections::HashMap;
+use std::{
+    io::{Read, Write},
+    time::SystemTime,
+};
+
+use crate::{
+    date::http_date,
+    header::{HeaderError, HeaderMap},
+};
+
+const SERVER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+type BodyWriter = Box<dyn FnOnce(&mut dyn Write) -> std::io::Result<()> + Send>;
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    //
+    //  Sent with `Transfer-Encoding: chunked`, one chunk per item, for bodies whose
+    //  length isn't known before they are produced
+    //
+    Chunked(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+    //
+    //  Copied from the reader as the response is written, framed by Content-Length when
+    //  the length is known and chunked otherwise
+    //
+    Reader(Box<dyn Read + Send>, Option<u64>),
+    //
+    //  Produced by writing into the connection, each write becomes a chunk
+    //
+    Writer(BodyWriter),
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Self::Chunked(_) => f.write_str("Chunked(..)"),
+            Self::Reader(_, length) => f.debug_tuple("Reader").field(length).finish(),
+            Self::Writer(_) => f.write_str("Writer(..)"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Response {
     status: usize,
-    headers: HashMap<String, String>,
-    body: Vec<u8>,
+    headers: HeaderMap,
+    body: Body,
+    //
+    //  The message of the error this response reports, if any, so an app's error handler
+    //  can render it in its own format
+    //
+    error: Option<String>,
 }
 
 impl Response {
     pub fn new() -> Self {
         Self {
             status: 200,
-            headers: HashMap::<String, String>::new(),
-            body: Vec::<u8>::new(),
+            headers: HeaderMap::new(),
+            body: Body::Bytes(Vec::<u8>::new()),
+            error: None,
         }
     }
 
+    //
+    //  Replaces any existing values of the header. An invalid name, or a value holding CR/LF,
+    //  is logged and the header left out rather than sent, see `try_set_header`.
+    //
     pub fn set_header(&mut self, k: &str, v: &str) {
-        self.headers.insert(k.to_string(), v.to_string());
+        if let Err(e) = self.try_set_header(k, v) {
+            println!("Skipping header: {}", e);
+        }
+    }
+
+    //
+    //  Adds another value for the header, e.g. a second Set-Cookie. Skips invalid headers
+    //  like `set_header`.
+    //
+    pub fn append_header(&mut self, k: &str, v: &str) {
+        if let Err(e) = self.try_append_header(k, v) {
+            println!("Skipping header: {}", e);
+        }
+    }
+
+    pub fn try_set_header(&mut self, k: &str, v: &str) -> Result<(), HeaderError> {
+        self.headers.insert(k, v)
+    }
+
+    pub fn try_append_header(&mut self, k: &str, v: &str) -> Result<(), HeaderError> {
+        self.headers.append(k, v)
+    }
+
+    pub fn get_header(&self, k: &str) -> Option<&String> {
+        self.headers.get(k)
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
     }
 
     pub fn set_body(&mut self, body: &str) {
-        self.body = body.as_bytes().to_vec();
+        self.body = Body::Bytes(body.as_bytes().to_vec());
+    }
+
+    pub fn set_body_bytes(&mut self, body: Vec<u8>) {
+        self.body = Body::Bytes(body);
+    }
+
+    pub fn set_body_chunks<I>(&mut self, chunks: I)
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        self.body = Body::Chunked(Box::new(chunks.into_iter()));
+    }
+
+    //
+    //  Stream the body out of `reader`, e.g. a `File`. If `length` is given, the reader has
+    //  to produce at least that many bytes.
+    //
+    pub fn set_body_reader<R>(&mut self, reader: R, length: Option<u64>)
+    where
+        R: Read + Send + 'static,
+    {
+        self.body = Body::Reader(Box::new(reader), length);
+    }
+
+    //
+    //  Stream the body by writing it once the headers have been sent, everything written is
+    //  sent on to the client when the writer is flushed
+    //
+    pub fn set_body_writer<F>(&mut self, writer: F)
+    where
+        F: FnOnce(&mut dyn Write) -> std::io::Result<()> + Send + 'static,
+    {
+        self.body = Body::Writer(Box::new(writer));
+    }
+
+    pub fn status(&self) -> usize {
+        self.status
     }
 
     pub fn set_status(&mut self, status: usize) {
         self.status = status;
     }
 
-    pub fn build_response(&self) -> Vec<u8> {
-        let protocol = "HTTP/1.1";
-        let status = self.status;
-        let headers = self.headers.iter().fold(String::new(), |acc, (k, v)| {
-            acc + &format!("{}: {}\r\n", k, v)
+    //
+    //  Turn the response into the framework's default error response, a JSON body of
+    //  `{"error": message}`, keeping any other headers already set
+    //
+    pub(crate) fn set_error(&mut self, status: usize, message: &str) {
+        let escaped = message.chars().fold(String::new(), |mut acc, c| {
+            match c {
+                '"' => acc.push_str("\\\""),
+                '\\' => acc.push_str("\\\\"),
+                c if (c as u32) < 0x20 => acc.push_str(&format!("\\u{:04x}", c as u32)),
+                c => acc.push(c),
+            }
+            acc
+        });
+        self.set_header("Content-Type", "application/json");
+        self.set_status(status);
+        self.set_body(&format!("{{\"error\": \"{}\"}}", escaped));
+        self.error = Some(message.to_string());
+    }
+
+    pub(crate) fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+
+    //
+    //  Serialize the response. Content-Length (or chunked framing), Date and Server are
+    //  filled in, and streamed bodies are consumed as they are written. `chunked` is false
+    //  for clients that don't understand chunked framing, i.e. HTTP/1.0 ones.
+    //
+    pub fn write_to<W: Write>(&mut self, writer: &mut W, chunked: bool) -> std::io::Result<()> {
+        self.write(writer, true, chunked)
+    }
+
+    //
+    //  Serialize the response as the answer to a HEAD request: the headers, including the
+    //  Content-Length the body would have had, but not the body itself
+    //
+    pub fn write_head_to<W: Write>(
+        &mut self,
+        writer: &mut W,
+        chunked: bool,
+    ) -> std::io::Result<()> {
+        self.write(writer, false, chunked)
+    }
+
+    fn write<W: Write>(
+        &mut self,
+        writer: &mut W,
+        with_body: bool,
+        chunked: bool,
+    ) -> std::io::Result<()> {
+        //
+        //  1xx, 204 and 304 responses never carry a body
+        //
+        let bodiless = matches!(self.status, 100..=199 | 204 | 304);
+        if bodiless {
+            self.body = Body::Bytes(Vec::<u8>::new());
+            self.headers.remove("Content-Length");
+        } else {
+            //
+            //  The body has to be framed by either a length or chunking, so a client can tell
+            //  where it ends on a connection that stays open
+            //
+            match &self.body {
+                Body::Bytes(bytes) => {
+                    let length = bytes.len().to_string();
+                    self.headers.remove("Transfer-Encoding");
+                    self.set_header("Content-Length", &length);
+                }
+                Body::Reader(_, Some(length)) => {
+                    let length = length.to_string();
+                    self.headers.remove("Transfer-Encoding");
+                    self.set_header("Content-Length", &length);
+                }
+                Body::Chunked(_) | Body::Reader(_, None) | Body::Writer(_) if chunked => {
+                    self.headers.remove("Content-Length");
+                    self.set_header("Transfer-Encoding", "chunked");
+                }
+                //
+                //  A client that can't read chunking gets the body as is, ended by closing
+                //  the connection
+                //
+                Body::Chunked(_) | Body::Reader(_, None) | Body::Writer(_) => {
+                    self.headers.remove("Content-Length");
+                    self.headers.remove("Transfer-Encoding");
+                    self.set_header("Connection", "close");
+                }
+            }
+        }
+        if !self.headers.contains("Date") {
+            self.set_header("Date", &http_date(SystemTime::now()));
+        }
+        if !self.headers.contains("Server") {
+            self.set_header("Server", SERVER);
+        }
+
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (k, v) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+
+        let body = std::mem::replace(&mut self.body, Body::Bytes(Vec::<u8>::new()));
+        if !with_body {
+            return writer.flush();
+        }
+        match body {
+            Body::Bytes(bytes) => writer.write_all(&bytes)?,
+            Body::Chunked(chunks) if chunked => {
+                let mut chunked = ChunkedWriter::new(writer);
+                for chunk in chunks {
+                    chunked.write_all(&chunk)?;
+                    chunked.flush()?;
+                }
+                chunked.finish()?;
+            }
+            Body::Chunked(chunks) => {
+                for chunk in chunks {
+                    writer.write_all(&chunk)?;
+                    writer.flush()?;
+                }
+            }
+            Body::Reader(reader, Some(length)) => {
+                let copied = std::io::copy(&mut reader.take(length), writer)?;
+                if copied < length {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "response body reader ended before its length",
+                    ));
+                }
+            }
+            Body::Reader(mut reader, None) if chunked => {
+                let mut chunked = ChunkedWriter::new(writer);
+                std::io::copy(&mut reader, &mut chunked)?;
+                chunked.finish()?;
+            }
+            Body::Reader(mut reader, None) => {
+                std::io::copy(&mut reader, writer)?;
+            }
+            Body::Writer(produce) if chunked => {
+                let mut chunked = ChunkedWriter::new(writer);
+                produce(&mut chunked)?;
+                chunked.finish()?;
+            }
+            Body::Writer(produce) => produce(writer)?,
+        }
+        writer.flush()
+    }
+
+    pub fn build_response(&mut self) -> Vec<u8> {
+        let mut buf = Vec::<u8>::new();
+        self.write_to(&mut buf, true)
+            .expect("writing to a Vec should never fail");
+        buf
+    }
+}
+
+//
+//  The framework's default error response: a JSON body of `{"error": message}`
+//
+pub(crate) fn error_response(status: usize, message: &str) -> Response {
+    let mut res = Response::new();
+    res.set_error(status, message);
+    res
+}
+
+//
+//  Writes everything written to it as chunks of a chunked body
+//
+struct ChunkedWriter<'a, W: Write> {
+    inner: &'a mut W,
+}
+
+impl<'a, W: Write> ChunkedWriter<'a, W> {
+    fn new(inner: &'a mut W) -> Self {
+        Self { inner }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !buf.is_empty() {
+            write!(self.inner, "{:x}\r\n", buf.len())?;
+            self.inner.write_all(buf)?;
+            self.inner.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn reason_phrase(status: usize) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Response;
+
+    #[test]
+    fn writes_status_line_and_framing() {
+        let mut res = Response::new();
+        res.set_status(404);
+        res.set_body("missing");
+
+        let built = String::from_utf8(res.build_response()).unwrap();
+        let (head, body) = built.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(head.contains("Content-Length: 7"));
+        assert!(head.contains("Date: "));
+        assert!(head.contains("Server: "));
+        assert_eq!(body, "missing");
+    }
+
+    #[test]
+    fn writes_binary_body_unchanged() {
+        let bytes = vec![0x1f, 0x8b, 0x00, 0xff, 0xfe];
+        let mut res = Response::new();
+        res.set_body_bytes(bytes.clone());
+
+        let built = res.build_response();
+        assert!(built.ends_with(&bytes));
+        assert!(String::from_utf8_lossy(&built).contains("Content-Length: 5"));
+    }
+
+    #[test]
+    fn streams_reader_and_writer_bodies() {
+        let mut res = Response::new();
+        res.set_body_reader(&b"file contents"[..], Some(4));
+        let built = String::from_utf8(res.build_response()).unwrap();
+        assert!(built.contains("Content-Length: 4"));
+        assert!(built.ends_with("\r\n\r\nfile"));
+
+        let mut res = Response::new();
+        res.set_body_writer(|w| {
+            w.write_all(b"abc")?;
+            w.flush()?;
+            write!(w, "{}", 42)
         });
-        let body = String::from_utf8_lossy(&self.body[..]);
-        let formatted = format!("{} {}\r\n{}\r\n\r\n{}\r\n", protocol, status, headers, body);
-        formatted.as_bytes().to_vec()
+        let built = String::from_utf8(res.build_response()).unwrap();
+        let (head, body) = built.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert_eq!(body, "3\r\nabc\r\n2\r\n42\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn writes_repeated_headers() {
+        let mut res = Response::new();
+        res.append_header("Set-Cookie", "a=1");
+        res.append_header("Set-Cookie", "b=2");
+        res.set_header("content-length", "99");
+
+        let built = String::from_utf8(res.build_response()).unwrap();
+        assert!(built.contains("Set-Cookie: a=1\r\n"));
+        assert!(built.contains("Set-Cookie: b=2\r\n"));
+        assert!(!built.contains("99"));
+    }
+
+    #[test]
+    fn skips_invalid_headers() {
+        let mut res = Response::new();
+        res.set_header("X-Next", "a\r\nSet-Cookie: admin=1");
+        res.append_header("Bad Name", "b");
+        assert!(res.try_set_header("X-Next", "a\r\nb").is_err());
+        assert!(res.try_append_header("X-Ok", "ok").is_ok());
+
+        let built = String::from_utf8(res.build_response()).unwrap();
+        assert!(!built.contains("X-Next"));
+        assert!(!built.contains("admin"));
+        assert!(!built.contains("Bad Name"));
+        assert!(built.contains("X-Ok: ok\r\n"));
+    }
+
+    #[test]
+    fn escapes_error_messages() {
+        let mut res = super::error_response(400, "bad \"value\"\n");
+        let built = String::from_utf8(res.build_response()).unwrap();
+        assert!(built.ends_with("{\"error\": \"bad \\\"value\\\"\\u000a\"}"));
+    }
+
+    #[test]
+    fn omits_body_for_no_content() {
+        let mut res = Response::new();
+        res.set_status(204);
+        res.set_body("ignored");
+
+        let built = String::from_utf8(res.build_response()).unwrap();
+        assert!(built.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(!built.contains("Content-Length"));
+        assert!(built.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn keeps_content_length_for_head() {
+        let mut res = Response::new();
+        res.set_body("hello");
+
+        let mut buf = Vec::<u8>::new();
+        res.write_head_to(&mut buf, true).unwrap();
+        let built = String::from_utf8(buf).unwrap();
+        assert!(built.contains("Content-Length: 5\r\n"));
+        assert!(built.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn writes_chunked_body() {
+        let mut res = Response::new();
+        res.set_header("Content-Length", "10");
+        res.set_body_chunks(vec![b"hello".to_vec(), Vec::new(), b", world".to_vec()]);
+
+        let built = String::from_utf8(res.build_response()).unwrap();
+        let (head, body) = built.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(body, "5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn writes_chunks_unframed_without_chunked_support() {
+        let mut res = Response::new();
+        res.set_body_chunks(vec![b"hello".to_vec(), b", world".to_vec()]);
+
+        let mut buf = Vec::<u8>::new();
+        res.write_to(&mut buf, false).unwrap();
+        let built = String::from_utf8(buf).unwrap();
+        let (head, body) = built.split_once("\r\n\r\n").unwrap();
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!(body, "hello, world");
     }
 }
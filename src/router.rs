@@ -1,101 +1,554 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt::Display, sync::Arc};
 
 use crate::{
-    matcher::RouteMatcher,
+    error::IntoResponse,
+    matcher::{RouteMatcher, RouteTree},
+    middleware::{Middleware, Next},
     request::{HttpMethod, Request},
     response::Response,
 };
 
-type RouteHandler = fn(&Request, &mut Response);
+pub type RouteHandler = Arc<dyn Fn(&Request, &mut Response) + Send + Sync + 'static>;
+
+//
+//  What a handler may return: nothing, or a `Result` whose error is logged and turned into
+//  an error response, e.g. a 400 for a parameter that failed to parse
+//
+pub trait HandlerOutput {
+    fn apply(self, req: &Request, res: &mut Response);
+}
+
+impl HandlerOutput for () {
+    fn apply(self, _: &Request, _: &mut Response) {}
+}
+
+impl<E: IntoResponse + Display> HandlerOutput for Result<(), E> {
+    fn apply(self, req: &Request, res: &mut Response) {
+        if let Err(e) = self {
+            println!(
+                "Handler error: {} {}: {}",
+                req.method().as_str(),
+                req.route(),
+                e
+            );
+            e.into_response(res);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteError {
+    InvalidPattern { path: String, reason: String },
+    Conflict { path: String, reason: String },
+}
+
+impl std::fmt::Display for RouteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidPattern { path, reason } => {
+                write!(f, "invalid route pattern {}: {}", path, reason)
+            }
+            Self::Conflict { path, reason } => write!(f, "conflicting route {}: {}", path, reason),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+//
+//  Box a handler, turning what it returns into the response, see `HandlerOutput`
+//
+pub(crate) fn route_handler<F, R>(handler: F) -> RouteHandler
+where
+    F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+    R: HandlerOutput,
+{
+    Arc::new(move |req: &Request, res: &mut Response| handler(req, res).apply(req, res))
+}
 
 #[derive(Clone)]
 pub struct Endpoint {
+    path: String,
     handler: RouteHandler,
     matcher: RouteMatcher,
+    //
+    //  The middleware of any routers nested below the one the endpoint is in, outermost first
+    //
+    middleware: Vec<Middleware>,
 }
 
 impl Endpoint {
-    pub fn new(path: String, handler: RouteHandler) -> Self {
-        let matcher = RouteMatcher::new(&path);
-        Self { handler, matcher }
+    pub fn new(path: String, handler: RouteHandler) -> Result<Self, RouteError> {
+        match RouteMatcher::new(&path) {
+            Ok(matcher) => Ok(Self {
+                path,
+                handler,
+                matcher,
+                middleware: Vec::<Middleware>::new(),
+            }),
+            Err(reason) => Err(RouteError::InvalidPattern { path, reason }),
+        }
+    }
+
+    //
+    //  An endpoint for everything under `prefix`, as used by a router's not-found handler
+    //
+    fn fallback(prefix: String, handler: RouteHandler) -> Result<Self, RouteError> {
+        let pattern = format!("{}/*rest", prefix.trim_end_matches('/'));
+        match RouteMatcher::new(&pattern) {
+            Ok(matcher) => Ok(Self {
+                path: prefix,
+                handler,
+                matcher,
+                middleware: Vec::<Middleware>::new(),
+            }),
+            Err(reason) => Err(RouteError::InvalidPattern {
+                path: prefix,
+                reason,
+            }),
+        }
     }
 
     pub fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
-        let matches = self.matcher.matches(path);
-        matches
+        self.matcher.matches(path)
     }
 }
 
+//
+//  A matched route: its handler, the params captured from the path and the middleware
+//  of every router between the one it was matched in and the route itself
+//
+pub struct RouteMatch {
+    pub handler: RouteHandler,
+    pub params: Option<HashMap<String, String>>,
+    pub middleware: Vec<Middleware>,
+}
+
 #[derive(Clone)]
 pub struct Router {
     path: String,
-    routes: HashMap<HttpMethod, Vec<Endpoint>>,
+    routes: HashMap<HttpMethod, RouteTree<Endpoint>>,
+    //
+    //  Every endpoint once, in the order it was added, so they can be mounted elsewhere;
+    //  the trees hold a copy per variant of a pattern with optional segments
+    //
+    endpoints: Vec<(HttpMethod, Endpoint)>,
+    //
+    //  Not-found handlers, this router's own and those of nested routers, for the paths
+    //  under their prefix that no route matches
+    //
+    fallbacks: Vec<Endpoint>,
+    middleware: Vec<Middleware>,
 }
 
 impl Router {
     pub fn new(path: &str) -> Self {
         Self {
             path: path.to_string(),
-            routes: HashMap::<HttpMethod, Vec<Endpoint>>::new(),
+            routes: HashMap::<HttpMethod, RouteTree<Endpoint>>::new(),
+            endpoints: Vec::<(HttpMethod, Endpoint)>::new(),
+            fallbacks: Vec::<Endpoint>::new(),
+            middleware: Vec::<Middleware>::new(),
         }
     }
 
-    pub fn get(&mut self, name: &str, handler: RouteHandler) {
-        self.add_route(name, HttpMethod::Get, handler);
+    pub fn get<F, R>(&mut self, name: &str, handler: F) -> Result<(), RouteError>
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.add_route(name, HttpMethod::Get, handler)
+    }
+
+    pub fn post<F, R>(&mut self, name: &str, handler: F) -> Result<(), RouteError>
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.add_route(name, HttpMethod::Post, handler)
+    }
+
+    pub fn put<F, R>(&mut self, name: &str, handler: F) -> Result<(), RouteError>
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.add_route(name, HttpMethod::Put, handler)
+    }
+
+    pub fn patch<F, R>(&mut self, name: &str, handler: F) -> Result<(), RouteError>
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.add_route(name, HttpMethod::Patch, handler)
+    }
+
+    pub fn head<F, R>(&mut self, name: &str, handler: F) -> Result<(), RouteError>
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.add_route(name, HttpMethod::Head, handler)
+    }
+
+    pub fn options<F, R>(&mut self, name: &str, handler: F) -> Result<(), RouteError>
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.add_route(name, HttpMethod::Options, handler)
     }
 
-    pub fn post(&mut self, name: &str, handler: RouteHandler) {
-        self.add_route(name, HttpMethod::Post, handler);
+    pub fn delete<F, R>(&mut self, name: &str, handler: F) -> Result<(), RouteError>
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.add_route(name, HttpMethod::Delete, handler)
     }
 
-    pub fn put(&mut self, name: &str, handler: RouteHandler) {
-        self.add_route(name, HttpMethod::Put, handler);
+    //
+    //  Fails if the pattern is invalid or clashes with a route already registered for the
+    //  method, so a mistake in the routes shows up at startup
+    //
+    pub fn add_route<F, R>(
+        &mut self,
+        name: &str,
+        method: HttpMethod,
+        handler: F,
+    ) -> Result<(), RouteError>
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        let endpoint = Endpoint::new(Self::format_path(&self.path, name), route_handler(handler))?;
+        self.insert(method, endpoint)
     }
 
-    pub fn patch(&mut self, name: &str, handler: RouteHandler) {
-        self.add_route(name, HttpMethod::Patch, handler);
+    //
+    //  Handle requests under this router's path that no route matches, in place of the
+    //  App's 404. Runs after the router's middleware, like a route would.
+    //
+    pub fn not_found<F, R>(&mut self, handler: F) -> Result<(), RouteError>
+    where
+        F: Fn(&Request, &mut Response) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        let fallback = Endpoint::fallback(self.path.clone(), route_handler(handler))?;
+        self.fallbacks.retain(|f| f.path != fallback.path);
+        self.fallbacks.push(fallback);
+        Ok(())
+    }
+
+    //
+    //  Mount every route of `router` under `prefix`, e.g. a sub-router's `/users/:id` becomes
+    //  `/api/v1/users/:id` when nested at `/api/v1`. Its middleware only runs for its own
+    //  routes, after the middleware of this router.
+    //
+    pub fn nest(&mut self, prefix: &str, router: Router) -> Result<(), RouteError> {
+        let prefix = Self::format_path(&self.path, prefix);
+        let mut endpoints = Vec::<(HttpMethod, Endpoint)>::new();
+        for (method, endpoint) in router.endpoints {
+            let mut mounted =
+                Endpoint::new(Self::mount_path(&prefix, &endpoint.path), endpoint.handler)?;
+            mounted.middleware = [&router.middleware[..], &endpoint.middleware[..]].concat();
+            endpoints.push((method, mounted));
+        }
+        let mut fallbacks = Vec::<Endpoint>::new();
+        for fallback in router.fallbacks {
+            let mut mounted =
+                Endpoint::fallback(Self::mount_path(&prefix, &fallback.path), fallback.handler)?;
+            mounted.middleware = [&router.middleware[..], &fallback.middleware[..]].concat();
+            fallbacks.push(mounted);
+        }
+
+        //
+        //  Every route is checked against this router's before any is added, so a conflict
+        //  leaves this router as it was
+        //
+        let mut routes = self.routes.clone();
+        for (method, endpoint) in &endpoints {
+            Self::insert_route(&mut routes, method, endpoint)?;
+        }
+        self.routes = routes;
+        self.endpoints.extend(endpoints);
+        for mounted in fallbacks {
+            self.fallbacks.retain(|f| f.path != mounted.path);
+            self.fallbacks.push(mounted);
+        }
+        Ok(())
     }
 
-    pub fn delete(&mut self, name: &str, handler: RouteHandler) {
-        self.add_route(name, HttpMethod::Delete, handler);
+    //
+    //  Where a nested router's `path` ends up under `prefix`. Its index route is the prefix
+    //  itself rather than `prefix/`.
+    //
+    fn mount_path(prefix: &str, path: &str) -> String {
+        match path {
+            "/" if prefix != "/" => prefix.trim_end_matches('/').to_string(),
+            path => Self::format_path(prefix, path),
+        }
     }
 
-    pub fn add_route(&mut self, name: &str, method: HttpMethod, handler: RouteHandler) {
-        self.routes
-            .entry(method)
-            .or_insert_with(Vec::<Endpoint>::new)
-            .push(Endpoint::new(Self::format_path(&self.path, name), handler));
+    fn insert(&mut self, method: HttpMethod, endpoint: Endpoint) -> Result<(), RouteError> {
+        Self::insert_route(&mut self.routes, &method, &endpoint)?;
+        self.endpoints.push((method, endpoint));
+        Ok(())
     }
 
-    pub fn match_handler(
-        &self,
+    fn insert_route(
+        routes: &mut HashMap<HttpMethod, RouteTree<Endpoint>>,
         method: &HttpMethod,
-        route: &str,
-    ) -> Option<(RouteHandler, Option<HashMap<String, String>>)> {
-        if let Some(handlers) = self.routes.get(method) {
-            if let Some(handler) = handlers
-                .iter()
-                .filter(|endpoint| match endpoint.matches(&route) {
-                    Some(_) => true,
-                    _ => false,
-                })
-                .collect::<Vec<&Endpoint>>()
-                .first()
-            {
-                Some((handler.handler, handler.matcher.matches(&route)))
-            } else {
-                None
-            }
-        } else {
-            None
+        endpoint: &Endpoint,
+    ) -> Result<(), RouteError> {
+        routes
+            .entry(method.clone())
+            .or_default()
+            .insert(&endpoint.matcher, endpoint.clone())
+            .map_err(|reason| RouteError::Conflict {
+                path: endpoint.path.clone(),
+                reason,
+            })
+    }
+
+    //
+    //  Middleware added here runs, in order, after any App middleware and
+    //  before the handler of a route matched by this router
+    //
+    pub fn use_middleware<F>(&mut self, middleware: F)
+    where
+        F: Fn(&mut Request, &mut Response, Next) + Send + Sync + 'static,
+    {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    pub fn middleware(&self) -> &[Middleware] {
+        &self.middleware
+    }
+
+    pub fn match_handler(&self, method: &HttpMethod, route: &str) -> Option<RouteMatch> {
+        let find = |method: &HttpMethod| self.routes.get(method).and_then(|tree| tree.find(route));
+        //
+        //  Without a route of its own, a HEAD request runs the GET handler and only its
+        //  headers are sent
+        //
+        let found = match method {
+            HttpMethod::Head => find(method).or_else(|| find(&HttpMethod::Get)),
+            method => find(method),
+        };
+        found.map(|(endpoint, params)| RouteMatch {
+            handler: Arc::clone(&endpoint.handler),
+            params: Some(params),
+            middleware: [&self.middleware[..], &endpoint.middleware[..]].concat(),
+        })
+    }
+
+    //
+    //  The not-found handler with the longest prefix covering `route`, if any
+    //
+    pub fn match_fallback(&self, route: &str) -> Option<RouteMatch> {
+        self.fallbacks
+            .iter()
+            .filter(|fallback| fallback.matches(route).is_some())
+            .max_by_key(|fallback| fallback.path.len())
+            .map(|fallback| RouteMatch {
+                handler: Arc::clone(&fallback.handler),
+                params: None,
+                middleware: [&self.middleware[..], &fallback.middleware[..]].concat(),
+            })
+    }
+
+    //
+    //  Every method with a route matching `route`, so a request under any other method can
+    //  be answered with a 405 rather than a 404
+    //
+    pub fn allowed_methods(&self, route: &str) -> Vec<HttpMethod> {
+        let mut methods: Vec<HttpMethod> = self
+            .routes
+            .iter()
+            .filter(|(_, tree)| tree.find(route).is_some())
+            .map(|(method, _)| method.clone())
+            .collect();
+        if methods.contains(&HttpMethod::Get) {
+            methods.push(HttpMethod::Head);
         }
+        methods.sort();
+        methods.dedup();
+        methods
     }
 
     fn format_path(base: &str, path: &str) -> String {
-        if base.ends_with("/") && path.starts_with("/") {
-            format!("{}{}", base.trim_end_matches("/"), path)
+        if base.ends_with('/') && path.starts_with('/') {
+            format!("{}{}", base.trim_end_matches('/'), path)
         } else {
             format!("{}{}", base, path)
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::{RouteError, Router};
+    use crate::{
+        extract::ParamError,
+        middleware::Next,
+        request::{HttpMethod, Request},
+        response::Response,
+    };
+
+    fn request(raw: &str) -> Request {
+        Request::parse(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn handlers_capture_state() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+        let mut router = Router::new("/");
+        router
+            .get("/count", move |_: &Request, _: &mut Response| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+
+        let cloned = router.clone();
+        for r in [&router, &cloned] {
+            let handler = r.match_handler(&HttpMethod::Get, "/count").unwrap().handler;
+            handler(
+                &request("GET /count HTTP/1.1\r\n\r\n"),
+                &mut Response::new(),
+            );
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn failed_extraction_becomes_bad_request() {
+        let mut router = Router::new("/");
+        router
+            .get(
+                "/user/:id",
+                |req: &Request, res: &mut Response| -> Result<(), ParamError> {
+                    let id = req.param::<u64>("id")?;
+                    res.set_body(&id.to_string());
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        let mut req = request("GET /user/abc HTTP/1.1\r\n\r\n");
+        let found = router.match_handler(&HttpMethod::Get, "/user/abc").unwrap();
+        req.set_url_params(found.params);
+        let mut res = Response::new();
+        (found.handler)(&req, &mut res);
+
+        let built = String::from_utf8(res.build_response()).unwrap();
+        assert!(built.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(built.contains("invalid path parameter 'id' = 'abc'"));
+    }
+
+    #[test]
+    fn reports_bad_routes_at_registration() {
+        let mut router = Router::new("/api");
+        router
+            .get("/user/:id", |_: &Request, _: &mut Response| {})
+            .unwrap();
+
+        let err = router
+            .get("/user/:name", |_: &Request, _: &mut Response| {})
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "conflicting route /api/user/:name: param ':name' conflicts with ':id'"
+        );
+        assert!(matches!(
+            router.post("/user/:id(\\d+", |_: &Request, _: &mut Response| {}),
+            Err(RouteError::InvalidPattern { .. })
+        ));
+        assert!(router
+            .post("/user/:name", |_: &Request, _: &mut Response| {})
+            .is_ok());
+    }
+
+    #[test]
+    fn lists_allowed_methods() {
+        let mut router = Router::new("/");
+        let noop = |_: &Request, _: &mut Response| {};
+        router.post("/user/:id", noop).unwrap();
+        router.get("/user/:id", noop).unwrap();
+        router.delete("/user/me", noop).unwrap();
+
+        assert_eq!(
+            router.allowed_methods("/user/7"),
+            vec![HttpMethod::Get, HttpMethod::Head, HttpMethod::Post]
+        );
+        assert_eq!(router.allowed_methods("/user/me").len(), 4);
+        assert!(router.allowed_methods("/post/7").is_empty());
+    }
+
+    #[test]
+    fn nests_routers_with_scoped_middleware() {
+        let mut users = Router::new("/users");
+        users.use_middleware(|req: &mut Request, res: &mut Response, next: Next| {
+            res.append_header("X-Trace", "users");
+            next.run(req, res);
+        });
+        users
+            .get("/:id", |req: &Request, res: &mut Response| {
+                res.set_body(req.get_url_param("id").unwrap());
+            })
+            .unwrap();
+        let mut api = Router::new("/");
+        api.get("/", |_: &Request, _: &mut Response| {}).unwrap();
+        api.use_middleware(|req: &mut Request, res: &mut Response, next: Next| {
+            res.append_header("X-Trace", "api");
+            next.run(req, res);
+        });
+        api.nest("/api/v1", users).unwrap();
+
+        let mut req = request("GET /api/v1/users/7 HTTP/1.1\r\n\r\n");
+        let found = api.match_handler(&HttpMethod::Get, req.route()).unwrap();
+        req.set_url_params(found.params);
+        let mut res = Response::new();
+        Next::new(&found.middleware, &found.handler).run(&mut req, &mut res);
+        assert_eq!(res.headers().get_all("X-Trace"), vec!["api", "users"]);
+        assert!(String::from_utf8(res.build_response())
+            .unwrap()
+            .ends_with("\r\n\r\n7"));
+
+        assert_eq!(
+            api.match_handler(&HttpMethod::Get, "/")
+                .unwrap()
+                .middleware
+                .len(),
+            1
+        );
+        assert!(api.match_handler(&HttpMethod::Get, "/users/7").is_none());
+
+        let mut dupe = Router::new("/");
+        dupe.get("/users/:name", |_: &Request, _: &mut Response| {})
+            .unwrap();
+        assert!(matches!(
+            api.nest("/api/v1", dupe),
+            Err(RouteError::Conflict { .. })
+        ));
+
+        let noop = |_: &Request, _: &mut Response| {};
+        let mut sub = Router::new("/");
+        sub.get("/a", noop).unwrap();
+        sub.get("/b", noop).unwrap();
+        sub.not_found(noop).unwrap();
+        let mut parent = Router::new("/");
+        parent.get("/x/b", noop).unwrap();
+        assert!(parent.nest("/x", sub).is_err());
+        assert!(parent.match_handler(&HttpMethod::Get, "/x/a").is_none());
+        assert!(parent.match_fallback("/x/c").is_none());
+        assert_eq!(parent.allowed_methods("/x/a"), Vec::<HttpMethod>::new());
+    }
+}
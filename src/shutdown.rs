@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+//
+//  Stops a running server, see `App::handle`. Cloned handles all stop the same server, and
+//  can be sent to other threads, e.g. a signal handler or a test.
+//
+#[derive(Clone, Default)]
+pub struct ServerHandle {
+    state: Arc<State>,
+}
+
+impl ServerHandle {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(State::default()),
+        }
+    }
+
+    //
+    //  Stop accepting connections and let the requests being handled finish, for at most
+    //  `grace`, before closing every connection that's left. Returns right away; `listen`
+    //  returns once the server has stopped, which is only after every handler has returned,
+    //  even one still running past `grace`.
+    //
+    pub fn shutdown(&self, grace: Duration) {
+        {
+            let mut deadline = self.state.lock_deadline();
+            if deadline.is_some() {
+                return;
+            }
+            *deadline = Some(Instant::now() + grace);
+        }
+        self.state.close_idle();
+        //
+        //  The accept loop only sees the request once it accepts something, so wake it up
+        //
+        if let Some(mut addr) = self.local_addr() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+                    SocketAddr::V6(_) => [0, 0, 0, 0, 0, 0, 0, 1].into(),
+                });
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+
+    //
+    //  The address the server is listening on, once it is, e.g. to find the port picked
+    //  when listening on port 0
+    //
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self
+            .state
+            .addr
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.lock_deadline().is_some()
+    }
+
+    pub(crate) fn listening(&self, addr: SocketAddr, workers: usize) {
+        self.state.workers.store(workers, Ordering::Relaxed);
+        *self
+            .state
+            .addr
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(addr);
+    }
+
+    //
+    //  Track a connection so shutting down can close it. When it has to wait for a worker,
+    //  an idle connection, if there is one, is closed to free one up.
+    //
+    pub(crate) fn register(&self, stream: &TcpStream) -> io::Result<ConnectionGuard> {
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Connection {
+            stream: stream.try_clone()?,
+            idle: false,
+        };
+        let mut connections = self.state.lock_connections();
+        connections.insert(id, connection);
+        if self.state.queued(&connections) {
+            let idle = connections
+                .iter()
+                .find_map(|(id, connection)| connection.idle.then_some(*id));
+            if let Some(connection) = idle.and_then(|id| connections.remove(&id)) {
+                let _ = connection.stream.shutdown(Shutdown::Both);
+            }
+        }
+        Ok(ConnectionGuard {
+            id,
+            state: Arc::clone(&self.state),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn idle_connections(&self) -> usize {
+        self.state
+            .lock_connections()
+            .values()
+            .filter(|connection| connection.idle)
+            .count()
+    }
+
+    //
+    //  Wait, up to the deadline, for the open connections to finish, then close the rest
+    //
+    pub(crate) fn drain(&self) {
+        let deadline = match *self.state.lock_deadline() {
+            Some(deadline) => deadline,
+            None => Instant::now(),
+        };
+        let mut connections = self.state.lock_connections();
+        while !connections.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                println!("Closing {} connections still open", connections.len());
+                for connection in connections.values() {
+                    let _ = connection.stream.shutdown(Shutdown::Both);
+                }
+                return;
+            }
+            connections = self
+                .state
+                .drained
+                .wait_timeout(connections, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    deadline: Mutex<Option<Instant>>,
+    addr: Mutex<Option<SocketAddr>>,
+    connections: Mutex<HashMap<u64, Connection>>,
+    next_id: AtomicU64,
+    drained: Condvar,
+    //
+    //  Threads serving connections, 0 until listening
+    //
+    workers: AtomicUsize,
+}
+
+struct Connection {
+    stream: TcpStream,
+    //
+    //  Waiting for the next request rather than handling one
+    //
+    idle: bool,
+}
+
+impl State {
+    fn lock_deadline(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.deadline.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_connections(&self) -> MutexGuard<'_, HashMap<u64, Connection>> {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    //
+    //  Whether some connections are waiting for a worker, every connection past the
+    //  `workers` first ones has to
+    //
+    fn queued(&self, connections: &HashMap<u64, Connection>) -> bool {
+        let workers = self.workers.load(Ordering::Relaxed);
+        workers > 0 && connections.len() > workers
+    }
+
+    fn close_idle(&self) {
+        for connection in self.lock_connections().values() {
+            if connection.idle {
+                let _ = connection.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+//
+//  A tracked connection, it's forgotten again when dropped
+//
+pub(crate) struct ConnectionGuard {
+    id: u64,
+    state: Arc<State>,
+}
+
+impl ConnectionGuard {
+    //
+    //  Mark the connection as waiting for its next request or not. Returns false, leaving it
+    //  as it was, when it should be closed instead: it was closed while idle to free its
+    //  worker, it would go idle while other connections wait for a worker, or the server is
+    //  shutting down. Once shutting down, an idle connection can't become busy since it may
+    //  have been closed already, and a busy one isn't kept open for another request.
+    //
+    pub(crate) fn set_idle(&self, idle: bool) -> bool {
+        let mut connections = self.state.lock_connections();
+        let shutting_down = self.state.lock_deadline().is_some();
+        let queued = self.state.queued(&connections);
+        let Some(connection) = connections.get_mut(&self.id) else {
+            return false;
+        };
+        if (shutting_down && (idle || connection.idle)) || (idle && queued) {
+            return false;
+        }
+        connection.idle = idle;
+        true
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.state.lock_connections();
+        connections.remove(&self.id);
+        if connections.is_empty() {
+            self.state.drained.notify_all();
+        }
+    }
+}
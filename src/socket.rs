@@ -0,0 +1,74 @@
+//
+//  Listening sockets with the options std's `TcpListener::bind` doesn't expose: the accept
+//  backlog, SO_REUSEADDR and SO_REUSEPORT
+//
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    pub backlog: u32,
+    pub reuse_address: bool,
+    pub reuse_port: bool,
+}
+
+pub fn bind(addr: SocketAddr, options: SocketOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(options.reuse_address)?;
+    if options.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(options.backlog.min(i32::MAX as u32) as i32)?;
+    Ok(socket.into())
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use std::net::{SocketAddr, TcpStream};
+
+    use super::{bind, SocketOptions};
+
+    #[test]
+    fn binds_with_reuse_port() {
+        let options = SocketOptions {
+            backlog: 16,
+            reuse_address: true,
+            reuse_port: true,
+        };
+        let first = bind("127.0.0.1:0".parse().unwrap(), options).unwrap();
+        let addr = first.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        let second = bind(addr, options).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+        TcpStream::connect(addr).unwrap();
+
+        let v6: SocketAddr = "[::1]:0".parse().unwrap();
+        if let Ok(listener) = bind(v6, options) {
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex, PoisonError},
     thread,
 };
 
@@ -33,14 +35,26 @@ struct Worker {
 impl Worker {
     fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Self {
         let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
+            //
+            //  The lock is only held while waiting, so a panicking job can't poison it, but
+            //  don't let one take every other worker down with it if it somehow does
+            //
+            let message = receiver
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .recv();
 
             match message {
-                Message::NewJob(job) => {
+                Ok(Message::NewJob(job)) => {
                     println!("Worker {} got a job", id);
-                    job();
+                    //
+                    //  A panicking job is logged and the worker moves on to the next one
+                    //
+                    if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        println!("Worker {} job panicked: {}", id, panic_message(&*panic));
+                    }
                 }
-                Message::Terminate => {
+                Ok(Message::Terminate) | Err(_) => {
                     println!("Worker {} terminated", id);
                     break;
                 }
@@ -51,10 +65,17 @@ impl Worker {
             thread: Some(thread),
         }
     }
+
+    fn is_dead(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_none_or(|thread| thread.is_finished())
+    }
 }
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
+    workers: Mutex<Vec<Worker>>,
+    receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
     sender: mpsc::Sender<Message>,
 }
 
@@ -67,7 +88,11 @@ impl ThreadPool {
             for id in 0..size {
                 workers.push(Worker::new(id, Arc::clone(&receiver)));
             }
-            Ok(ThreadPool { workers, sender })
+            Ok(ThreadPool {
+                workers: Mutex::new(workers),
+                receiver,
+                sender,
+            })
         } else {
             Err(ThreadPoolError::ZeroSize)
         }
@@ -77,6 +102,7 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
+        self.respawn_dead_workers();
         let job = Box::new(f);
         if let Err(e) = self.sender.send(Message::NewJob(job)) {
             Err(ThreadPoolError::ThreadExecution(e.to_string()))
@@ -84,18 +110,75 @@ impl ThreadPool {
             Ok(())
         }
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+    //
+    //  Stop every worker once it has run the jobs queued before this call, and wait for
+    //  them. A later `execute` starts them again.
+    //
+    pub fn join(&self) {
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        for _ in workers.iter().filter(|worker| worker.thread.is_some()) {
+            let _ = self.sender.send(Message::Terminate);
         }
-        for worker in &mut self.workers {
+        for worker in workers.iter_mut() {
             if let Some(thread) = worker.thread.take() {
                 println!("Shutting down worker {}", worker.id);
-                thread.join().unwrap();
+                if thread.join().is_err() {
+                    println!("Worker {} panicked", worker.id);
+                }
             }
         }
     }
+
+    //
+    //  Jobs can't kill a worker, but anything that does anyway, e.g. a panic while logging,
+    //  would otherwise shrink the pool for good
+    //
+    fn respawn_dead_workers(&self) {
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        for worker in workers.iter_mut().filter(|worker| worker.is_dead()) {
+            println!("Respawning worker {}", worker.id);
+            *worker = Worker::new(worker.id, Arc::clone(&self.receiver));
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+//
+//  The message a panic was raised with, when it's a string
+//
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::ThreadPool;
+
+    #[test]
+    fn keeps_working_after_a_job_panics() {
+        let pool = ThreadPool::new(1).unwrap();
+        let (sender, receiver) = mpsc::channel::<usize>();
+        pool.execute(|| panic!("job failed")).unwrap();
+        for i in 0..3 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
+        }
+        for i in 0..3 {
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(i));
+        }
+    }
 }
@@ -0,0 +1,78 @@
+//
+//  application/x-www-form-urlencoded decoding, as used by query strings and form bodies
+//
+
+//
+//  Split `input` into its decoded key/value pairs, in order. A pair without an `=` has an
+//  empty value, and empty pairs (e.g. from `a=1&&b=2`) are skipped.
+//
+pub fn parse(input: &str) -> Vec<(String, String)> {
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) => (decode(k), decode(v)),
+            None => (decode(pair), String::new()),
+        })
+        .collect()
+}
+
+//
+//  Replace `+` with a space and `%XX` escapes with the byte they encode. Invalid escapes are
+//  kept as they are, and byte sequences that aren't UTF-8 are replaced.
+//
+pub fn decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::<u8>::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match (
+                bytes.get(i + 1).and_then(|b| hex(*b)),
+                bytes.get(i + 2).and_then(|b| hex(*b)),
+            ) {
+                (Some(hi), Some(lo)) => {
+                    decoded.push(hi << 4 | lo);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, parse};
+
+    #[test]
+    fn decodes_escapes_and_plus() {
+        assert_eq!(decode("hello+world%21"), "hello world!");
+        assert_eq!(decode("caf%C3%A9"), "café");
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+        assert_eq!(decode("a%2Bb"), "a+b");
+    }
+
+    #[test]
+    fn parses_pairs_in_order() {
+        assert_eq!(
+            parse("tag=a&tag=b+c&flag&&empty=&q=1%3D2"),
+            vec![
+                ("tag".to_string(), "a".to_string()),
+                ("tag".to_string(), "b c".to_string()),
+                ("flag".to_string(), String::new()),
+                ("empty".to_string(), String::new()),
+                ("q".to_string(), "1=2".to_string()),
+            ]
+        );
+    }
+}